@@ -0,0 +1,160 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use structopt::StructOpt;
+
+use kvs::{error, EncryptionKey, KvsError};
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "kvs-admin", about = "Kvs administration tools.")]
+struct Opt {
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Backs up the sealed generations of a data directory, copying only the
+    /// files which earlier backups into the same directory do not hold
+    #[structopt(name = "backup")]
+    Backup {
+        #[structopt(index = 1, required = true, parse(from_os_str))]
+        data_dir: PathBuf,
+        #[structopt(index = 2, required = true, parse(from_os_str))]
+        backup_dir: PathBuf,
+    },
+
+    /// Reassembles a data directory from a base backup and its increments
+    #[structopt(name = "restore")]
+    Restore {
+        #[structopt(index = 1, required = true, parse(from_os_str))]
+        backup_dir: PathBuf,
+        #[structopt(index = 2, required = true, parse(from_os_str))]
+        data_dir: PathBuf,
+        /// Restore this increment instead of the latest one
+        #[structopt(long)]
+        increment: Option<u64>,
+    },
+
+    /// Checks every entry in a data directory's logs against its checksum
+    /// and length, reporting corrupt ranges and counting live and dead
+    /// entries
+    #[structopt(name = "verify")]
+    Verify {
+        #[structopt(index = 1, required = true, parse(from_os_str))]
+        data_dir: PathBuf,
+        /// Decrypts the logs with the key in this file
+        #[structopt(long, value_name = "PATH", parse(from_os_str))]
+        key_file: Option<PathBuf>,
+    },
+
+    /// Copies a sled engine's database written by sled 0.22, which older
+    /// versions of kvs used, into a new directory in the current format
+    #[structopt(name = "migrate-sled")]
+    MigrateSled {
+        #[structopt(index = 1, required = true, parse(from_os_str))]
+        old_dir: PathBuf,
+        #[structopt(index = 2, required = true, parse(from_os_str))]
+        new_dir: PathBuf,
+    },
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> error::Result<()> {
+    match opt.command {
+        Command::Backup {
+            data_dir,
+            backup_dir,
+        } => {
+            let stats = kvs::backup(&data_dir, &backup_dir)?;
+            println!(
+                "Backed up increment {}: copied {} of {} files ({} bytes)",
+                stats.increment, stats.copied, stats.files, stats.copied_bytes
+            );
+        }
+        Command::Restore {
+            backup_dir,
+            data_dir,
+            increment,
+        } => kvs::restore(&backup_dir, increment, &data_dir)?,
+        Command::Verify { data_dir, key_file } => {
+            let key = key_file.map(EncryptionKey::from_file).transpose()?;
+            let report = kvs::verify(&data_dir, key.as_ref())?;
+            for corrupt in &report.corrupt {
+                println!(
+                    "Corrupt bytes {}..{} in generation {}: {}",
+                    corrupt.range.start, corrupt.range.end, corrupt.gen, corrupt.error
+                );
+            }
+            if let Some(torn) = &report.torn {
+                println!(
+                    "Torn write at offset {} in generation {}, which opening the store drops",
+                    torn.range.start, torn.gen
+                );
+            }
+            println!(
+                "Verified {} generations: {} entries, {} live, {} dead",
+                report.generations,
+                report.entries,
+                report.live,
+                report.dead()
+            );
+            if !report.is_ok() {
+                return Err(KvsError::String(format!(
+                    "Found {} corrupt ranges",
+                    report.corrupt.len()
+                )));
+            }
+        }
+        Command::MigrateSled { old_dir, new_dir } => {
+            let pairs = migrate_sled(&old_dir, &new_dir)?;
+            println!("Migrated {} keys", pairs);
+        }
+    }
+    Ok(())
+}
+
+/// Copies every key in the sled 0.22 database in `old_dir` into a new
+/// database in `new_dir`, which `SledKvsEngine` can open, returning the
+/// number of keys copied.
+fn migrate_sled(old_dir: &Path, new_dir: &Path) -> error::Result<usize> {
+    if !old_dir.is_dir() {
+        return Err(KvsError::String(format!(
+            "No sled database in {}",
+            old_dir.display()
+        )));
+    }
+    if new_dir.exists() && fs::read_dir(new_dir)?.next().is_some() {
+        return Err(KvsError::String(format!(
+            "Migration directory {} is not empty",
+            new_dir.display()
+        )));
+    }
+
+    let old_error = |e: sled_022::Error| {
+        KvsError::String(format!(
+            "Cannot read sled 0.22 database in {}: {}",
+            old_dir.display(),
+            e
+        ))
+    };
+    let old = sled_022::Db::start_default(old_dir).map_err(old_error)?;
+    let new = sled::open(new_dir)?;
+
+    let mut pairs = 0;
+    for pair in old.iter() {
+        let (key, value) = pair.map_err(old_error)?;
+        new.insert(key, value.to_vec())?;
+        pairs += 1;
+    }
+    new.flush()?;
+    Ok(pairs)
+}
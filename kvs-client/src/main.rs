@@ -1,5 +1,6 @@
 use std::net::SocketAddr;
 use std::process::exit;
+use std::time::{Duration, SystemTime};
 
 use structopt::StructOpt;
 
@@ -23,6 +24,15 @@ enum Command {
         key: String,
         #[structopt(index = 2, required = true)]
         value: String,
+        /// Expire the key after this long, e.g. `30s` or `1h 30m`
+        #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+        ttl: Option<Duration>,
+        /// Only set the key if it does not exist
+        #[structopt(long, conflicts_with_all = &["xx", "ttl"])]
+        nx: bool,
+        /// Only set the key if it already exists
+        #[structopt(long, conflicts_with = "ttl")]
+        xx: bool,
         #[structopt(short, long, parse(try_from_str), default_value = DEFAULT_LISTENING_ADDRESS)]
         addr: SocketAddr,
     },
@@ -35,6 +45,42 @@ enum Command {
         addr: SocketAddr,
     },
 
+    /// Set a key to a new value only if it currently has the expected one
+    #[structopt(name = "cas")]
+    CompareAndSwap {
+        #[structopt(index = 1, required = true)]
+        key: String,
+        /// The value the key must have; if omitted, the key must not exist
+        #[structopt(long)]
+        expected: Option<String>,
+        /// The value to set; if omitted, the key is removed
+        #[structopt(long)]
+        new: Option<String>,
+        #[structopt(short, long, required = false, default_value = DEFAULT_LISTENING_ADDRESS)]
+        addr: SocketAddr,
+    },
+
+    /// Print how long a key has left before it expires
+    #[structopt(name = "ttl")]
+    Ttl {
+        #[structopt(index = 1, required = true)]
+        key: String,
+        #[structopt(short, long, required = false, default_value = DEFAULT_LISTENING_ADDRESS)]
+        addr: SocketAddr,
+    },
+
+    /// Make an existing key expire at a point in time
+    #[structopt(name = "expireat")]
+    ExpireAt {
+        #[structopt(index = 1, required = true)]
+        key: String,
+        /// When the key expires, e.g. `2030-01-01T00:00:00Z`
+        #[structopt(index = 2, required = true, parse(try_from_str = humantime::parse_rfc3339_weak))]
+        at: SystemTime,
+        #[structopt(short, long, required = false, default_value = DEFAULT_LISTENING_ADDRESS)]
+        addr: SocketAddr,
+    },
+
     #[structopt(name = "rm")]
     Remove {
         #[structopt(index = 1, required = true)]
@@ -62,9 +108,42 @@ fn run(opt: Opt) -> error::Result<()> {
                 println!("Key not found");
             }
         }
-        Command::Set { key, value, addr } => {
+        Command::Set {
+            key,
+            value,
+            ttl,
+            nx,
+            xx,
+            addr,
+        } => {
+            let client = KvsClient::connect(addr)?;
+            match ttl {
+                Some(ttl) => client.set_with_ttl(key, value, ttl)?,
+                None if nx => client.set_if_absent(key, value)?,
+                None if xx => client.set_if_present(key, value)?,
+                None => client.set(key, value)?,
+            }
+        }
+        Command::CompareAndSwap {
+            key,
+            expected,
+            new,
+            addr,
+        } => {
+            let client = KvsClient::connect(addr)?;
+            client.compare_and_swap(key, expected, new)?;
+        }
+        Command::Ttl { key, addr } => {
+            let client = KvsClient::connect(addr)?;
+            if let Some(ttl) = client.ttl(key)? {
+                println!("{}", humantime::format_duration(ttl));
+            } else {
+                println!("No expiry");
+            }
+        }
+        Command::ExpireAt { key, at, addr } => {
             let client = KvsClient::connect(addr)?;
-            client.set(key, value)?;
+            client.expire_at(key, at)?;
         }
         Command::Remove { key, addr } => {
             let client = KvsClient::connect(addr)?;
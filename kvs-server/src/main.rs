@@ -2,9 +2,9 @@ use std::env;
 use std::env::current_dir;
 use std::fs;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
 
-use env_logger;
 use log::LevelFilter;
 use structopt::StructOpt;
 
@@ -15,7 +15,10 @@ extern crate log;
 extern crate clap;
 
 use kvs::error;
-use kvs::{KvStore, KvsEngine, KvsServer, SledKvsEngine};
+use kvs::{
+    Codec, CompactionTrigger, EncryptionKey, KvStore, KvStoreOptions, KvsEngine, KvsError,
+    KvsServer, SledKvsEngine, SyncPolicy,
+};
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
@@ -39,6 +42,60 @@ struct Opt {
     )]
     engine: Option<Engine>,
 
+    #[structopt(
+        long,
+        help = "Sets the data subdirectory of the kvs engine. Defaults to .kvsdata",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    data_dir: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Compacts the log once this many bytes are stale",
+        value_name = "BYTES",
+        conflicts_with = "compaction-ratio"
+    )]
+    compaction_bytes: Option<u64>,
+    #[structopt(
+        long,
+        help = "Compacts the log once the ratio of stale to live bytes exceeds this",
+        value_name = "RATIO"
+    )]
+    compaction_ratio: Option<f64>,
+    #[structopt(
+        long,
+        help = "Starts a new log file once the current one reaches this many bytes. Defaults to \
+                64 MiB",
+        value_name = "BYTES"
+    )]
+    max_file_size: Option<u64>,
+    #[structopt(
+        long,
+        help = "Sets when writes are synced to disk: always, never, every-millis:<N> or \
+                every-bytes:<N>. Defaults to never for kvs and always for sled",
+        value_name = "POLICY"
+    )]
+    sync: Option<SyncPolicy>,
+    #[structopt(
+        long,
+        help = "Sets the codec the kvs engine compresses values with",
+        value_name = "CODEC",
+        possible_values = &["none", "snappy", "deflate"]
+    )]
+    compression: Option<Codec>,
+    #[structopt(
+        long,
+        help = "Encrypts the kvs engine's logs with the key in this file, which holds 32 bytes \
+                or 64 hexadecimal digits",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    key_file: Option<PathBuf>,
+    #[structopt(long, help = "Memory-maps the kvs engine's sealed log files for reads")]
+    mmap: bool,
+    #[structopt(long, help = "Opens the kvs engine read-only")]
+    read_only: bool,
+
     #[structopt(short, long, parse(from_occurrences))]
     verbosity: usize,
 }
@@ -53,8 +110,13 @@ arg_enum! {
 }
 
 fn main() {
-    env_logger::builder().filter_level(LevelFilter::Info).init();
     let mut opt = Opt::from_args();
+    let level = match opt.verbosity {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    env_logger::builder().filter_level(level).init();
     let res = current_engine().and_then(move |curr_engine| {
         if opt.engine.is_none() {
             opt.engine = curr_engine;
@@ -80,15 +142,80 @@ fn run(opt: Opt) -> error::Result<()> {
     info!("Storage engine: {}", engine);
     info!("Listening on {}", opt.addr);
 
-    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
-
+    if engine == Engine::sled {
+        check_sled_flags(&opt)?;
+    }
+    // The engine is only recorded once it has opened the directory, and never
+    // by a read-only server, which must leave the directory as it found it.
     match engine {
-        Engine::kvs => run_with_engine(KvStore::open(env::current_dir()?)?, opt.addr),
-        Engine::sled => run_with_engine(
-            SledKvsEngine::new(sled::Db::start_default(env::current_dir()?)?),
-            opt.addr,
-        ),
+        Engine::kvs => {
+            let store = KvStore::open_with(env::current_dir()?, kvs_options(&opt)?)?;
+            if !opt.read_only {
+                record_engine(engine)?;
+            }
+            run_with_engine(store, opt.addr)
+        }
+        Engine::sled => {
+            let mut sled_engine = SledKvsEngine::new(sled::open(env::current_dir()?)?)?;
+            if let Some(sync) = opt.sync {
+                sled_engine = sled_engine.sync_policy(sync);
+            }
+            record_engine(engine)?;
+            run_with_engine(sled_engine, opt.addr)
+        }
+    }
+}
+
+/// Fails on the first flag given which only the kvs engine supports, rather
+/// than running without it.
+fn check_sled_flags(opt: &Opt) -> error::Result<()> {
+    let kvs_only = [
+        ("--data-dir", opt.data_dir.is_some()),
+        ("--compaction-bytes", opt.compaction_bytes.is_some()),
+        ("--compaction-ratio", opt.compaction_ratio.is_some()),
+        ("--max-file-size", opt.max_file_size.is_some()),
+        ("--compression", opt.compression.is_some()),
+        ("--key-file", opt.key_file.is_some()),
+        ("--mmap", opt.mmap),
+        ("--read-only", opt.read_only),
+    ];
+    match kvs_only.iter().find(|(_, given)| *given) {
+        Some((flag, _)) => Err(KvsError::String(format!(
+            "{} is not supported by the sled engine",
+            flag
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn kvs_options(opt: &Opt) -> error::Result<KvStoreOptions> {
+    let mut options = KvStoreOptions::new()
+        .mmap_reads(opt.mmap)
+        .read_only(opt.read_only);
+
+    if let Some(data_dir) = &opt.data_dir {
+        options = options.data_dir(data_dir);
+    }
+    if let Some(sync) = opt.sync {
+        options = options.sync_policy(sync);
+    }
+    if let Some(bytes) = opt.compaction_bytes {
+        options = options.compaction_trigger(CompactionTrigger::Bytes(bytes));
+    }
+    if let Some(ratio) = opt.compaction_ratio {
+        options = options.compaction_trigger(CompactionTrigger::Ratio(ratio));
+    }
+    if let Some(max_file_size) = opt.max_file_size {
+        options = options.max_file_size(max_file_size);
+    }
+    if let Some(compression) = opt.compression {
+        options = options.compression(compression);
     }
+    if let Some(key_file) = &opt.key_file {
+        options = options.encryption_key(EncryptionKey::from_file(key_file)?);
+    }
+
+    Ok(options)
 }
 
 fn run_with_engine<E: KvsEngine>(engine: E, addr: SocketAddr) -> error::Result<()> {
@@ -96,6 +223,11 @@ fn run_with_engine<E: KvsEngine>(engine: E, addr: SocketAddr) -> error::Result<(
     server.run(addr)
 }
 
+fn record_engine(engine: Engine) -> error::Result<()> {
+    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
+    Ok(())
+}
+
 fn current_engine() -> error::Result<Option<Engine>> {
     let engine = current_dir()?.join("engine");
     if !engine.exists() {
@@ -1,21 +1,22 @@
 #[macro_use]
 extern crate criterion;
 
-use criterion::{BatchSize, Criterion};
+use std::thread;
+
+use criterion::{BenchmarkId, Criterion, Throughput};
 use rand::rngs::SmallRng;
 use rand::Rng;
 use rand_core::SeedableRng;
-use sled;
 use tempfile::TempDir;
 
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, SledKvsEngine, SyncPolicy};
 
 fn bench_set(c: &mut Criterion) {
     let mut group = c.benchmark_group("Set");
 
     group.bench_function("kvs.set", |b| {
         let temp_dir = TempDir::new().unwrap();
-        let mut db = KvStore::open(temp_dir.path()).unwrap();
+        let db = KvStore::open(temp_dir.path()).unwrap();
         b.iter(|| {
             for key_i in 1..(1 << 8) {
                 db.set(format!("key{}", key_i), "value").unwrap();
@@ -25,7 +26,7 @@ fn bench_set(c: &mut Criterion) {
 
     group.bench_function("sled.set", |b| {
         let temp_dir = TempDir::new().unwrap();
-        let mut db = SledKvsEngine::new(sled::Db::start_default(&temp_dir).unwrap());
+        let db = SledKvsEngine::new(sled::open(&temp_dir).unwrap()).unwrap();
         b.iter(|| {
             for key_i in 1..(1 << 8) {
                 db.set(format!("key{}", key_i), "value").unwrap();
@@ -41,7 +42,7 @@ fn bench_get(c: &mut Criterion) {
 
     group.bench_function("kvs.get", |b| {
         let temp_dir = TempDir::new().unwrap();
-        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
 
         for key_i in 1..(1 << 8) {
             store.set(format!("key{}", key_i), "value").unwrap();
@@ -57,7 +58,7 @@ fn bench_get(c: &mut Criterion) {
 
     group.bench_function("sled.get", |b| {
         let temp_dir = TempDir::new().unwrap();
-        let mut db = SledKvsEngine::new(sled::Db::start_default(&temp_dir).unwrap());
+        let db = SledKvsEngine::new(sled::open(&temp_dir).unwrap()).unwrap();
 
         for key_i in 1..(1 << 8) {
             db.set(format!("key{}", key_i), "value").unwrap();
@@ -72,5 +73,87 @@ fn bench_get(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, bench_set, bench_get);
+fn bench_sealed_get(c: &mut Criterion) {
+    const KEYS: usize = 1 << 12;
+
+    let mut group = c.benchmark_group("Get from sealed generations");
+
+    for &mmap_reads in &[false, true] {
+        let name = if mmap_reads { "kvs.mmap" } else { "kvs.pread" };
+        group.bench_function(name, |b| {
+            let temp_dir = TempDir::new().unwrap();
+            let options = KvStoreOptions::new().max_file_size(1 << 20);
+            let store = KvStore::open_with(temp_dir.path(), options.clone()).unwrap();
+            for key_i in 0..KEYS {
+                store.set(format!("key{}", key_i), "v".repeat(256)).unwrap();
+            }
+            drop(store);
+
+            // Every generation is sealed once the store is reopened.
+            let store =
+                KvStore::open_with(temp_dir.path(), options.mmap_reads(mmap_reads)).unwrap();
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                store.get(format!("key{}", rng.gen_range(0, KEYS))).unwrap();
+            })
+        });
+    }
+
+    group.finish();
+}
+
+/// Sets `writes` keys split evenly across `threads` threads.
+fn concurrent_set(engine: &impl KvsEngine, threads: usize, writes: usize) {
+    thread::scope(|scope| {
+        for thread_i in 0..threads {
+            let engine = engine.clone();
+            scope.spawn(move || {
+                for key_i in 0..writes / threads {
+                    engine
+                        .set(format!("key{}-{}", thread_i, key_i), "value")
+                        .unwrap();
+                }
+            });
+        }
+    });
+}
+
+fn bench_concurrent_set(c: &mut Criterion) {
+    const WRITES: usize = 512;
+
+    let mut group = c.benchmark_group("Concurrent durable set");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(WRITES as u64));
+
+    for &threads in &[1, 8, 64] {
+        group.bench_with_input(BenchmarkId::new("kvs", threads), &threads, |b, &threads| {
+            let temp_dir = TempDir::new().unwrap();
+            let options = KvStoreOptions::new().sync_policy(SyncPolicy::Always);
+            let store = KvStore::open_with(temp_dir.path(), options).unwrap();
+            b.iter(|| concurrent_set(&store, threads, WRITES));
+        });
+
+        group.bench_with_input(
+            BenchmarkId::new("sled", threads),
+            &threads,
+            |b, &threads| {
+                let temp_dir = TempDir::new().unwrap();
+                let db = SledKvsEngine::new(sled::open(&temp_dir).unwrap())
+                    .unwrap()
+                    .sync_policy(SyncPolicy::Always);
+                b.iter(|| concurrent_set(&db, threads, WRITES));
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_set,
+    bench_get,
+    bench_sealed_get,
+    bench_concurrent_set
+);
 criterion_main!(benches);
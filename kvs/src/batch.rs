@@ -0,0 +1,59 @@
+use crate::entry::Entry;
+
+/// A group of puts and deletes which are applied atomically.
+///
+/// Operations are applied in the order they were added, so a later operation
+/// on a key overrides an earlier one. Deleting a key which does not exist is
+/// not an error.
+///
+/// # Examples
+///
+/// ```
+/// use kvs::{KvStore, KvsEngine, WriteBatch};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().unwrap();
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+/// store.set("from", "10").unwrap();
+///
+/// let mut batch = WriteBatch::new();
+/// batch.delete("from");
+/// batch.put("to", "10");
+/// store.write_batch(batch).unwrap();
+///
+/// assert_eq!(store.get("from").unwrap(), None);
+/// assert_eq!(store.get("to").unwrap(), Some(String::from("10")));
+/// ```
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    pub(crate) entries: Vec<Entry>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds setting a key to a value to the batch.
+    pub fn put(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.entries.push(Entry::set(key, value));
+        self
+    }
+
+    /// Adds removing a key to the batch.
+    pub fn delete(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.entries.push(Entry::remove(key));
+        self
+    }
+
+    /// Returns the number of operations in the batch.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if the batch has no operations.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
@@ -1,8 +1,12 @@
+use std::convert::TryInto;
 use std::io::{BufReader, BufWriter, Write};
 use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::error;
+use crate::request::Request;
 use crate::response;
+use crate::KvsError;
 
 /// Key-value store client.
 pub struct KvsClient {
@@ -22,31 +26,121 @@ impl KvsClient {
     }
 
     /// Sets a key to a value via the server.
-    pub fn set(mut self, key: String, value: String) -> error::Result<()> {
-        self.writer
-            .write_all(format!("+\r\n{}\r\n{}\r\n", key, value).as_bytes())?;
-        self.writer.flush()?;
-        response::from_reader(&mut self.reader)?;
+    pub fn set(self, key: String, value: String) -> error::Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
 
+    /// Sets a key to a value which expires after `ttl` via the server.
+    pub fn set_with_ttl(self, key: String, value: String, ttl: Duration) -> error::Result<()> {
+        self.request(Request::SetWithTtl {
+            key: key.into_bytes(),
+            value: value.into_bytes(),
+            ttl_millis: ttl.as_millis() as u64,
+        })?;
         Ok(())
     }
 
     /// Gets a key via the server.
-    pub fn get(mut self, key: String) -> error::Result<Option<String>> {
-        self.writer
-            .write_all(format!("?\r\n{}\r\n", key).as_bytes())?;
-        self.writer.flush()?;
-
-        response::from_reader(&mut self.reader)
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::FromUtf8` if the value is not valid UTF-8.
+    pub fn get(self, key: String) -> error::Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
     }
 
     /// Removes a key via the server.
-    pub fn remove(mut self, key: String) -> error::Result<()> {
-        self.writer
-            .write_all(format!("-\r\n{}\r\n", key).as_bytes())?;
-        self.writer.flush()?;
-        response::from_reader(&mut self.reader)?;
+    pub fn remove(self, key: String) -> error::Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Sets a key to `new` via the server, provided its current value is
+    /// `expected`.
+    ///
+    /// `None` stands for a missing key in both.
+    pub fn compare_and_swap(
+        self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> error::Result<()> {
+        self.request(Request::CompareAndSwap {
+            key: key.into_bytes(),
+            expected: expected.map(String::into_bytes),
+            new: new.map(String::into_bytes),
+        })?;
+        Ok(())
+    }
+
+    /// Sets a key to a value via the server, provided it does not exist.
+    pub fn set_if_absent(self, key: String, value: String) -> error::Result<()> {
+        self.request(Request::SetIfAbsent {
+            key: key.into_bytes(),
+            value: value.into_bytes(),
+        })?;
+        Ok(())
+    }
 
+    /// Sets a key to a value via the server, provided it already exists.
+    pub fn set_if_present(self, key: String, value: String) -> error::Result<()> {
+        self.request(Request::SetIfPresent {
+            key: key.into_bytes(),
+            value: value.into_bytes(),
+        })?;
         Ok(())
     }
+
+    /// Gets how long a key has left before it expires via the server, or
+    /// `None` if it never does.
+    pub fn ttl(self, key: String) -> error::Result<Option<Duration>> {
+        self.request(Request::Ttl {
+            key: key.into_bytes(),
+        })?
+        .map(|millis| {
+            let millis = millis
+                .as_slice()
+                .try_into()
+                .map_err(|_| KvsError::String(String::from("Malformed response")))?;
+            Ok(Duration::from_millis(u64::from_le_bytes(millis)))
+        })
+        .transpose()
+    }
+
+    /// Makes an existing key expire at a point in time via the server.
+    pub fn expire_at(self, key: String, at: SystemTime) -> error::Result<()> {
+        // A time before the epoch has passed just as surely as the epoch.
+        let at = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.request(Request::ExpireAt {
+            key: key.into_bytes(),
+            at_millis: at.as_millis() as u64,
+        })?;
+        Ok(())
+    }
+
+    /// Sets a binary key to a binary value via the server.
+    pub fn set_bytes(self, key: Vec<u8>, value: Vec<u8>) -> error::Result<()> {
+        self.request(Request::Set { key, value })?;
+        Ok(())
+    }
+
+    /// Gets a binary key via the server.
+    pub fn get_bytes(self, key: Vec<u8>) -> error::Result<Option<Vec<u8>>> {
+        self.request(Request::Get { key })
+    }
+
+    /// Removes a binary key via the server.
+    pub fn remove_bytes(self, key: Vec<u8>) -> error::Result<()> {
+        self.request(Request::Remove { key })?;
+        Ok(())
+    }
+
+    fn request(mut self, req: Request) -> error::Result<Option<Vec<u8>>> {
+        req.to_writer(&mut self.writer)?;
+        self.writer.flush()?;
+
+        response::from_reader(&mut self.reader)
+    }
 }
@@ -0,0 +1,93 @@
+use std::convert::TryInto;
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A source of the current time, which decides when keys expire.
+///
+/// Stores use the `SystemClock` unless told otherwise; tests can swap in a
+/// `ManualClock` to control expiration.
+pub trait Clock: Debug + Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> SystemTime;
+}
+
+/// A clock which reads the system time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock which only moves when told to.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::{Duration, UNIX_EPOCH};
+/// use kvs::{Clock, ManualClock};
+///
+/// let clock = ManualClock::new(UNIX_EPOCH);
+/// clock.advance(Duration::from_secs(30));
+/// assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_secs(30));
+/// ```
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<SystemTime>,
+}
+
+impl ManualClock {
+    /// Creates a clock which starts at the given time.
+    pub fn new(now: SystemTime) -> Self {
+        Self {
+            now: Mutex::new(now),
+        }
+    }
+
+    /// Sets the current time.
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    /// Moves the current time forward.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// Converts a time to milliseconds since the Unix epoch, as stored on disk.
+///
+/// Times before the epoch are clamped to it, and times too far after it are
+/// clamped to `u64::MAX`.
+pub(crate) fn to_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis().try_into().unwrap_or(u64::MAX))
+}
+
+/// Returns when a value set at `now` with the time to live `ttl` expires, in
+/// milliseconds since the Unix epoch, or `None` if that is too far off to
+/// store, in which case the value never expires.
+pub(crate) fn expiry(now: SystemTime, ttl: Duration) -> Option<u64> {
+    to_millis(now).checked_add(ttl.as_millis().try_into().ok()?)
+}
+
+/// Returns `true` if a value with the given expiry time has expired by
+/// `now`, both in milliseconds since the Unix epoch.
+pub(crate) fn is_expired(expires_at: Option<u64>, now: u64) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= now)
+}
+
+/// Returns how long a value with the given expiry time has left to live at
+/// `now`, or `None` if it never expires.
+pub(crate) fn remaining(expires_at: Option<u64>, now: u64) -> Option<Duration> {
+    expires_at.map(|expires_at| Duration::from_millis(expires_at.saturating_sub(now)))
+}
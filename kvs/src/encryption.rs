@@ -0,0 +1,146 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use crate::{KvsError, Result};
+
+/// The size of an encryption key in bytes.
+pub const KEY_SIZE: usize = 32;
+
+/// The number of bytes sealing adds to a message: its nonce followed by its
+/// authentication tag.
+pub const SEAL_OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+
+/// What a key check seals, so that it cannot be mistaken for an entry.
+const KEY_CHECK_AAD: &[u8] = b"kvs key check";
+
+/// A key which encrypts a store's logs with XChaCha20-Poly1305.
+///
+/// Every message is sealed under a fresh random 192-bit nonce, which is long
+/// enough that a key never repeats one in practice.
+///
+/// # Examples
+///
+/// ```
+/// use kvs::{EncryptionKey, KvStore, KvStoreOptions, KvsEngine};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().unwrap();
+/// let key = EncryptionKey::new([7; 32]);
+/// let options = KvStoreOptions::new().encryption_key(key);
+///
+/// let store = KvStore::open_with(temp_dir.path(), options).unwrap();
+/// store.set("foo", "bar").unwrap();
+/// ```
+#[derive(Clone)]
+pub struct EncryptionKey {
+    cipher: XChaCha20Poly1305,
+}
+
+impl EncryptionKey {
+    /// Creates a key from its raw bytes.
+    pub fn new(bytes: [u8; KEY_SIZE]) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(&bytes.into()),
+        }
+    }
+
+    /// Reads a key from a file holding either its 32 raw bytes or 64
+    /// hexadecimal digits.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let contents = fs::read(path)?;
+        let invalid = || {
+            KvsError::String(String::from(
+                "Key file must hold 32 bytes or 64 hexadecimal digits",
+            ))
+        };
+
+        let mut bytes = [0; KEY_SIZE];
+        if contents.len() == KEY_SIZE {
+            bytes.copy_from_slice(&contents);
+            return Ok(Self::new(bytes));
+        }
+
+        let hex = std::str::from_utf8(&contents)
+            .map_err(|_| invalid())?
+            .trim();
+        if hex.len() != KEY_SIZE * 2 {
+            return Err(invalid());
+        }
+        for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits).map_err(|_| invalid())?;
+            *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+        }
+        Ok(Self::new(bytes))
+    }
+
+    /// Encrypts and authenticates `plaintext`, along with the unencrypted
+    /// `aad`, under a fresh random nonce.
+    ///
+    /// Returns the nonce followed by the ciphertext and its tag.
+    pub(crate) fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .expect("Message is too large to encrypt");
+
+        let mut sealed = Vec::with_capacity(SEAL_OVERHEAD + plaintext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Authenticates and decrypts a message sealed with [`seal`].
+    ///
+    /// Returns `None` if the message was sealed with another key or with
+    /// different `aad`, or has been tampered with.
+    ///
+    /// [`seal`]: #method.seal
+    pub(crate) fn open(&self, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < SEAL_OVERHEAD {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .ok()
+    }
+
+    /// Returns a check which proves that a log was encrypted with this key,
+    /// without revealing it.
+    pub(crate) fn key_check(&self) -> [u8; SEAL_OVERHEAD] {
+        let mut check = [0; SEAL_OVERHEAD];
+        check.copy_from_slice(&self.seal(KEY_CHECK_AAD, &[]));
+        check
+    }
+
+    /// Returns `true` if a key check was made with this key.
+    pub(crate) fn verify(&self, check: &[u8; SEAL_OVERHEAD]) -> bool {
+        self.open(KEY_CHECK_AAD, check).is_some()
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
@@ -0,0 +1,308 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use crate::engines::sync;
+use crate::error;
+use crate::KvsError;
+
+use super::hint::hint_path;
+use super::lock::DirLock;
+use super::manifest::{Manifest, ManifestFile};
+use super::{log_path, sorted_gen_list};
+
+/// The name of a backup directory's catalog.
+const CATALOG_FILE: &str = "CATALOG";
+
+/// The first line of a catalog, naming its format.
+const CATALOG_HEADER: &str = "kvs backup catalog 1";
+
+/// How many times a backup tries to copy logs which a writer keeps
+/// compacting away.
+const BACKUP_ATTEMPTS: usize = 5;
+
+/// What an incremental backup copied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackupStats {
+    /// The number of the increment the backup was recorded as, counting from
+    /// 0 for the base backup.
+    pub increment: u64,
+    /// The number of files the backed up store is made of.
+    pub files: usize,
+    /// The number of files which were not in an earlier increment, and so
+    /// were copied into this one.
+    pub copied: usize,
+    /// The number of bytes copied into this increment.
+    pub copied_bytes: u64,
+}
+
+/// Takes an incremental backup of the sealed generations in the data
+/// directory `log_dir` into `backup_dir`.
+///
+/// Every backup is recorded as a numbered increment: a subdirectory of
+/// `backup_dir` holding a manifest of every log and hint file in the store,
+/// along with copies of those which no earlier increment holds. The catalog
+/// in `backup_dir` records which increment holds each file, by name, length
+/// and checksum. The first backup into an empty directory is the base, which
+/// copies every file.
+///
+/// Only sealed generations are backed up. If a store is writing to
+/// `log_dir`, its active generation is left out, so the backup holds every
+/// write up to the last time the store rotated its log or took a checkpoint.
+/// Otherwise `log_dir` is locked for the duration of the backup, and every
+/// generation is backed up.
+///
+/// # Errors
+///
+/// Fails if a running store keeps compacting its log while it is copied, or
+/// if any file cannot be read or written.
+///
+/// # Examples
+///
+/// ```
+/// use kvs::{KvStore, KvsEngine};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().unwrap();
+/// let backup_dir = TempDir::new().unwrap();
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+/// store.set("foo", "bar").unwrap();
+/// drop(store);
+///
+/// let log_dir = temp_dir.path().join(".kvsdata");
+/// let stats = kvs::backup(&log_dir, backup_dir.path()).unwrap();
+/// assert_eq!(stats.increment, 0);
+/// let stats = kvs::backup(&log_dir, backup_dir.path()).unwrap();
+/// assert_eq!((stats.increment, stats.copied), (1, 0));
+/// ```
+pub fn backup(log_dir: &Path, backup_dir: &Path) -> error::Result<BackupStats> {
+    let lock = match DirLock::acquire(log_dir) {
+        Ok(lock) => Some(lock),
+        Err(KvsError::Locked { .. }) => None,
+        Err(e) => return Err(e),
+    };
+
+    fs::create_dir_all(backup_dir)?;
+    let mut catalog = Catalog::read(backup_dir)?;
+    let increment = catalog.increments.last().map_or(0, |last| last + 1);
+    let increment_dir = backup_dir.join(increment.to_string());
+
+    for _ in 0..BACKUP_ATTEMPTS {
+        // An increment which is not in the catalog was cut short, either by a
+        // crash or by a compaction in the previous attempt.
+        if increment_dir.exists() {
+            fs::remove_dir_all(&increment_dir)?;
+        }
+        fs::create_dir(&increment_dir)?;
+
+        let (manifest, copied) =
+            match copy_sealed(log_dir, lock.is_some(), &catalog, &increment_dir) {
+                Ok(Some(res)) => res,
+                Ok(None) => continue,
+                Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+        manifest.write(&increment_dir)?;
+
+        let stats = BackupStats {
+            increment,
+            files: manifest.files.len(),
+            copied: copied.len(),
+            copied_bytes: copied.iter().map(|file| file.len).sum(),
+        };
+        catalog
+            .files
+            .extend(copied.into_iter().map(|file| (increment, file)));
+        catalog.increments.push(increment);
+        catalog.write(backup_dir)?;
+        return Ok(stats);
+    }
+
+    fs::remove_dir_all(&increment_dir)?;
+    Err(KvsError::String(format!(
+        "Logs in {} kept changing while they were backed up",
+        log_dir.display()
+    )))
+}
+
+/// Reassembles the data directory `log_dir` as it was backed up in
+/// `increment` of `backup_dir`, or in the latest increment if it is `None`.
+///
+/// Each file is copied from whichever increment holds it, and checked
+/// against its checksum. `log_dir` must be empty or not exist yet; an
+/// encrypted store can only be opened with the key it was backed up with.
+pub fn restore(backup_dir: &Path, increment: Option<u64>, log_dir: &Path) -> error::Result<()> {
+    let catalog = Catalog::read(backup_dir)?;
+    let increment = match increment {
+        Some(increment) if catalog.increments.contains(&increment) => increment,
+        Some(increment) => {
+            return Err(KvsError::String(format!(
+                "Backup has no increment {}",
+                increment
+            )))
+        }
+        None => *catalog
+            .increments
+            .last()
+            .ok_or_else(|| KvsError::String(String::from("Backup has no increments")))?,
+    };
+
+    fs::create_dir_all(log_dir)?;
+    if fs::read_dir(log_dir)?.next().is_some() {
+        return Err(KvsError::String(format!(
+            "Restore directory {} is not empty",
+            log_dir.display()
+        )));
+    }
+
+    let manifest = Manifest::read(&backup_dir.join(increment.to_string()))?;
+    for file in &manifest.files {
+        let holder = catalog
+            .files
+            .iter()
+            .filter(|(holder, backed_up)| *holder <= increment && backed_up == file)
+            .map(|&(holder, _)| holder)
+            .next_back()
+            .ok_or_else(|| KvsError::String(format!("Backup holds no copy of {}", file.name)))?;
+
+        let dest = log_dir.join(&file.name);
+        fs::copy(backup_dir.join(holder.to_string()).join(&file.name), &dest)?;
+        File::open(&dest)?.sync_all()?;
+        if ManifestFile::of(&dest)? != *file {
+            return Err(KvsError::String(format!(
+                "Backup copy of {} in increment {} does not match its checksum",
+                file.name, holder
+            )));
+        }
+    }
+    manifest.write(log_dir)
+}
+
+/// Copies the sealed log and hint files in `log_dir` which the catalog does
+/// not hold yet into `increment_dir`, returning a manifest of every sealed
+/// file along with the ones it copied.
+///
+/// Returns `None` if a compaction replaced any of the generations while they
+/// were being copied. A compaction deletes every generation older than its
+/// output, so the set of generations up to the newest one copied only stays
+/// the same if none ran.
+fn copy_sealed(
+    log_dir: &Path,
+    all_sealed: bool,
+    catalog: &Catalog,
+    increment_dir: &Path,
+) -> error::Result<Option<(Manifest, Vec<ManifestFile>)>> {
+    let mut gens = sorted_gen_list(log_dir)?;
+    if !all_sealed {
+        // The newest generation is the one the writer appends to.
+        gens.pop();
+    }
+
+    let mut manifest = Manifest::default();
+    let mut copied = vec![];
+    for &gen in &gens {
+        for (path, required) in [
+            (log_path(log_dir, gen), true),
+            (hint_path(log_dir, gen), false),
+        ] {
+            let file = match ManifestFile::of(&path) {
+                Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound && !required => {
+                    continue
+                }
+                res => res?,
+            };
+            if !catalog
+                .files
+                .iter()
+                .any(|(_, backed_up)| *backed_up == file)
+            {
+                let dest = increment_dir.join(&file.name);
+                fs::copy(&path, &dest)?;
+                File::open(&dest)?.sync_all()?;
+                copied.push(file.clone());
+            }
+            manifest.files.push(file);
+        }
+    }
+    sync::sync_dir(increment_dir)?;
+
+    let newest = gens.last().copied().unwrap_or(0);
+    let gens_after: Vec<_> = sorted_gen_list(log_dir)?
+        .into_iter()
+        .filter(|&gen| gen <= newest)
+        .collect();
+    if gens_after != gens {
+        warn!("Generations in {} changed during backup", log_dir.display());
+        return Ok(None);
+    }
+    Ok(Some((manifest, copied)))
+}
+
+/// The record of every file a backup directory holds, and of the increments
+/// which were completed.
+///
+/// A catalog is a text file with a header line followed by one line per
+/// file, `file <increment> <name> <length> <crc>`, and one line per
+/// completed increment, `increment <increment>`.
+#[derive(Debug, Default)]
+struct Catalog {
+    files: Vec<(u64, ManifestFile)>,
+    increments: Vec<u64>,
+}
+
+impl Catalog {
+    /// Reads the catalog in `backup_dir`, or returns an empty one if there is
+    /// none yet.
+    fn read(backup_dir: &Path) -> error::Result<Self> {
+        let file = match File::open(backup_dir.join(CATALOG_FILE)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            res => res?,
+        };
+        let invalid = |line: &str| KvsError::String(format!("Invalid catalog line: {}", line));
+
+        let mut catalog = Self::default();
+        let mut lines = BufReader::new(file).lines();
+        match lines.next().transpose()? {
+            Some(ref header) if header == CATALOG_HEADER => {}
+            header => return Err(invalid(&header.unwrap_or_default())),
+        }
+        for line in lines {
+            let line = line?;
+            let (kind, rest) = line.split_once(' ').ok_or_else(|| invalid(&line))?;
+            match kind {
+                "file" => {
+                    let (increment, file) = rest.split_once(' ').ok_or_else(|| invalid(&line))?;
+                    let increment = increment.parse().map_err(|_| invalid(&line))?;
+                    catalog.files.push((increment, ManifestFile::parse(file)?));
+                }
+                "increment" => {
+                    let increment = rest.parse().map_err(|_| invalid(&line))?;
+                    catalog.increments.push(increment);
+                }
+                _ => return Err(invalid(&line)),
+            }
+        }
+        Ok(catalog)
+    }
+
+    /// Writes the catalog into `backup_dir`, replacing the one already there.
+    ///
+    /// The catalog is written under a temporary name and renamed into place,
+    /// so an increment is only recorded once every file in it is complete.
+    fn write(&self, backup_dir: &Path) -> error::Result<()> {
+        let path = backup_dir.join(CATALOG_FILE);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        writeln!(file, "{}", CATALOG_HEADER)?;
+        for (increment, backed_up) in &self.files {
+            writeln!(file, "file {} {}", increment, backed_up)?;
+        }
+        for increment in &self.increments {
+            writeln!(file, "increment {}", increment)?;
+        }
+        file.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        sync::sync_dir(backup_dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+use crate::error;
+use crate::KvsError;
+
+use super::hint::hint_path;
+use super::manifest::{Manifest, ManifestFile};
+use super::{log_path, stale_path, Generation};
+
+/// Links or copies the logs and hint files of `gens` from `log_dir` into
+/// `dest`, along with a manifest listing them.
+///
+/// Every generation must be sealed and pinned, so that none of them is
+/// written to or deleted while this runs.
+pub fn checkpoint(log_dir: &Path, gens: &[Generation], dest: &Path) -> error::Result<()> {
+    fs::create_dir_all(dest)?;
+    if fs::read_dir(dest)?.next().is_some() {
+        return Err(KvsError::String(format!(
+            "Checkpoint directory {} is not empty",
+            dest.display()
+        )));
+    }
+
+    let mut manifest = Manifest::default();
+    for &gen in gens {
+        // A generation compacted away since it was pinned is moved to its
+        // stale path, which may happen while it is being linked.
+        let file = match link_or_copy(&log_path(log_dir, gen), &log_path(dest, gen)) {
+            Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {
+                link_or_copy(&stale_path(log_dir, gen), &log_path(dest, gen))?
+            }
+            res => res?,
+        };
+        manifest.files.push(file);
+
+        // Compaction may remove a pinned generation's hint file at any time,
+        // but the log alone is enough to open the checkpoint.
+        match link_or_copy(&hint_path(log_dir, gen), &hint_path(dest, gen)) {
+            Ok(file) => manifest.files.push(file),
+            Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    manifest.write(dest)
+}
+
+/// Hard-links `src` to `dest`, or copies it if they are on different
+/// filesystems, and syncs it, returning its manifest entry.
+fn link_or_copy(src: &Path, dest: &Path) -> error::Result<ManifestFile> {
+    match fs::hard_link(src, dest) {
+        Err(ref e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            fs::copy(src, dest)?;
+        }
+        res => res?,
+    }
+
+    File::open(dest)?.sync_all()?;
+    ManifestFile::of(dest)
+}
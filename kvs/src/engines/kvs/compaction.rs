@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::mem;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+
+use crate::clock;
+use crate::engines::sync;
+use crate::entry;
+use crate::error;
+
+use super::hint::{self, Hint, Hints};
+use super::snapshot::Pins;
+use super::{log_path, sorted_gen_list, stale_path, BufWriterWithPos, LogReader};
+use super::{EntryPos, Expired, Generation, KeyDir, KvStoreOptions};
+
+/// A compaction running on a background thread.
+pub struct Compaction {
+    /// The generations the live entries may be copied into.
+    pub gens: Range<Generation>,
+    /// The stale bytes there were when the compaction started, which it
+    /// reclaims if it succeeds.
+    pub uncompacted: u64,
+    // The total length of the live entries the compaction moved, before and
+    // after it rewrote them, which the writer has not accounted for yet.
+    resized: Arc<Mutex<(u64, u64)>>,
+    handle: JoinHandle<error::Result<()>>,
+}
+
+impl Compaction {
+    /// Spawns a thread which compacts every generation older than `gens`
+    /// into as many of `gens` as it takes to keep each one under the maximum
+    /// file size.
+    ///
+    /// The caller must already be writing to a generation newer than `gens`,
+    /// so that nothing else is appended to the generations being compacted.
+    ///
+    /// If the compaction fails before the keydir points at its output, the
+    /// output is removed again and the stale generations are left as they
+    /// were.
+    pub fn spawn(
+        log_dir: PathBuf,
+        options: KvStoreOptions,
+        keydir: Arc<RwLock<KeyDir>>,
+        pins: Arc<Mutex<Pins>>,
+        expired: Arc<Expired>,
+        gens: Range<Generation>,
+        uncompacted: u64,
+    ) -> Self {
+        let resized = Arc::new(Mutex::new((0, 0)));
+        let handle = thread::Builder::new()
+            .name(format!("kvs-compaction-{}", gens.start))
+            .spawn({
+                let gens = gens.clone();
+                let resized = Arc::clone(&resized);
+                move || {
+                    let res = compact(
+                        &log_dir,
+                        &options,
+                        &keydir,
+                        &pins,
+                        &expired,
+                        &resized,
+                        gens.clone(),
+                    );
+                    if res.is_err() {
+                        for gen in gens {
+                            let _ = fs::remove_file(compacting_path(&log_dir, gen));
+                            let _ = fs::remove_file(log_path(&log_dir, gen));
+                            let _ = hint::remove(&log_dir, gen);
+                        }
+                    }
+                    res
+                }
+            })
+            .expect("Cannot spawn compaction thread");
+
+        Self {
+            gens,
+            uncompacted,
+            resized,
+            handle,
+        }
+    }
+
+    /// Takes the total length of the live entries the compaction has moved
+    /// since this was last called, before and after it rewrote them.
+    ///
+    /// Entries are moved with the keydir locked, so with the keydir locked
+    /// this accounts for every entry in it which the compaction resized.
+    pub fn take_resized(&self) -> (u64, u64) {
+        mem::take(&mut *self.resized.lock().unwrap())
+    }
+
+    /// Returns `true` if the compaction thread has finished.
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Waits for the compaction thread to finish, returning the lengths of
+    /// the entries it resized which were not taken yet.
+    pub fn join(self) -> error::Result<(u64, u64)> {
+        self.handle.join().expect("Compaction thread panicked")?;
+        Ok(mem::take(&mut *self.resized.lock().unwrap()))
+    }
+}
+
+/// Copies every live entry from generations older than `compaction_gens`
+/// into `compaction_gens`, swaps the copied positions into the keydir and
+/// deletes the stale generations.
+///
+/// A new generation is started whenever the current one reaches the maximum
+/// file size. The last of `compaction_gens` takes whatever is left, so it may
+/// outgrow the maximum if rewriting entries made them larger than the writer
+/// reserved room for.
+///
+/// Entries from logs in an older format, or which are not encrypted while the
+/// store should be, are rewritten in the current one, compressed and
+/// encrypted as the options say; the rest are copied verbatim, keeping
+/// whichever codec they were written with.
+///
+/// Each new generation is written under a temporary name and only renamed
+/// into place once it is complete, so a crash never leaves a partial
+/// generation behind for `KvStore::open` to replay. The complete ones only
+/// hold copies of entries still in the stale generations, so replaying them
+/// is harmless.
+///
+/// Positions are only swapped for keys which were not written to while the
+/// copy was in progress; those keys already point at a newer generation.
+/// Rewriting an entry may change its length, so the lengths of the swapped
+/// entries are added to `resized` along with the swap.
+///
+/// Entries which have expired are not copied. Their keys are removed from
+/// the keydir and handed to the store through `expired`, again unless they
+/// were written to in the meantime.
+///
+/// Stale generations which a snapshot still refers to are moved to their
+/// stale path rather than deleted. Once the keydir points at the new
+/// generations the compaction has succeeded, so failing to remove the stale
+/// ones is only logged; whatever is left of them is removed by the next
+/// compaction.
+fn compact(
+    log_dir: &Path,
+    options: &KvStoreOptions,
+    keydir: &RwLock<KeyDir>,
+    pins: &Mutex<Pins>,
+    expired: &Expired,
+    resized: &Mutex<(u64, u64)>,
+    compaction_gens: Range<Generation>,
+) -> error::Result<()> {
+    let compaction_gen = compaction_gens.start;
+    let now = clock::to_millis(options.clock.now());
+    let (dropped, live): (Vec<(Vec<u8>, EntryPos)>, _) = keydir
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(_, entry_pos)| entry_pos.gen < compaction_gen)
+        .map(|(key, &entry_pos)| (key.clone(), entry_pos))
+        .partition(|(_, entry_pos)| entry_pos.is_expired(now));
+
+    let mut readers = HashMap::new();
+    let encryption_key = options.encryption_key.as_ref();
+    let mut output_gen = compaction_gen;
+    let mut compaction_writer = new_output(log_dir, options, output_gen)?;
+
+    let mut hints = Hints::new();
+    let mut moved = Vec::with_capacity(live.len());
+    for (key, entry_pos) in live {
+        if compaction_writer.pos >= options.max_file_size && output_gen + 1 < compaction_gens.end {
+            finish_output(log_dir, options, output_gen, compaction_writer, &hints)?;
+            output_gen += 1;
+            compaction_writer = new_output(log_dir, options, output_gen)?;
+            hints.clear();
+        }
+
+        let log_reader = match readers.get_mut(&entry_pos.gen) {
+            Some(log_reader) => log_reader,
+            None => {
+                let log_reader = LogReader::open(log_dir, entry_pos.gen, encryption_key)?;
+                readers.entry(entry_pos.gen).or_insert(log_reader)
+            }
+        };
+
+        let pos = compaction_writer.pos;
+        // Sealed entries are bound to where they were written, so they are
+        // sealed afresh rather than copied.
+        if !log_reader.is_outdated(options) && encryption_key.is_none() {
+            compaction_writer.write_all(&log_reader.read_bytes(entry_pos)?)?;
+        } else {
+            let entry = log_reader.read(entry_pos)?;
+            entry::to_writer(
+                &mut compaction_writer,
+                &entry,
+                options.compression,
+                options.compression_threshold,
+                encryption_key,
+                output_gen,
+                pos,
+            )?;
+        }
+        let new_pos = EntryPos {
+            gen: output_gen,
+            pos,
+            len: compaction_writer.pos - pos,
+            ..entry_pos
+        };
+
+        hints.insert(key.clone(), Hint::Put(new_pos));
+        moved.push((key, entry_pos, new_pos));
+    }
+    finish_output(log_dir, options, output_gen, compaction_writer, &hints)?;
+    // The compacted generations must be durable before the generations they
+    // replace are deleted.
+    sync::sync_dir(log_dir)?;
+
+    {
+        let mut keydir = keydir.write().unwrap();
+        let mut resized = resized.lock().unwrap();
+        for (key, old_pos, new_pos) in moved {
+            if let Some(entry_pos) = keydir.get_mut(&key) {
+                if *entry_pos == old_pos {
+                    *entry_pos = new_pos;
+                    resized.0 += old_pos.len;
+                    resized.1 += new_pos.len;
+                }
+            }
+        }
+        let mut expired = expired.lock().unwrap();
+        for (key, old_pos) in dropped {
+            if keydir.get(&key) == Some(&old_pos) {
+                keydir.remove(&key);
+                expired.push((key, old_pos));
+            }
+        }
+    }
+
+    if let Err(e) = remove_stale(log_dir, pins, compaction_gen) {
+        warn!(
+            "Failed to remove generations compacted into {:?}: {}",
+            compaction_gens, e
+        );
+    }
+
+    Ok(())
+}
+
+/// Removes every generation older than `compaction_gen`, or moves it to its
+/// stale path if a snapshot still refers to it.
+fn remove_stale(
+    log_dir: &Path,
+    pins: &Mutex<Pins>,
+    compaction_gen: Generation,
+) -> error::Result<()> {
+    for stale_gen in sorted_gen_list(log_dir)? {
+        if stale_gen < compaction_gen {
+            hint::remove(log_dir, stale_gen)?;
+            // The lock is held across the rename, so that the last snapshot
+            // to unpin the generation cannot miss it.
+            let mut pins = pins.lock().unwrap();
+            if pins.retire(stale_gen) {
+                fs::rename(log_path(log_dir, stale_gen), stale_path(log_dir, stale_gen))?;
+            } else {
+                fs::remove_file(log_path(log_dir, stale_gen))?;
+            }
+        }
+    }
+    sync::sync_dir(log_dir)?;
+
+    Ok(())
+}
+
+/// Starts writing a generation of a compaction's output.
+fn new_output(
+    log_dir: &Path,
+    options: &KvStoreOptions,
+    gen: Generation,
+) -> error::Result<BufWriterWithPos<File>> {
+    let mut writer = BufWriterWithPos::with_capacity(
+        options.write_buffer_size,
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(compacting_path(log_dir, gen))?,
+    )?;
+    entry::write_header(&mut writer, gen, options.encryption_key.as_ref())?;
+    Ok(writer)
+}
+
+/// Syncs a complete generation of a compaction's output, renames it into
+/// place and writes its hint file.
+fn finish_output(
+    log_dir: &Path,
+    options: &KvStoreOptions,
+    gen: Generation,
+    mut writer: BufWriterWithPos<File>,
+    hints: &Hints,
+) -> error::Result<()> {
+    writer.sync()?;
+    fs::rename(compacting_path(log_dir, gen), log_path(log_dir, gen))?;
+    hint::write(
+        log_dir,
+        gen,
+        writer.pos,
+        hints,
+        options.encryption_key.as_ref(),
+    )
+}
+
+/// Returns the path a compaction writes to before it is complete.
+pub fn compacting_path(log_dir: &Path, gen: Generation) -> PathBuf {
+    log_path(log_dir, gen).with_extension("log.compacting")
+}
+
+/// Removes the output of any compaction which was interrupted by a crash,
+/// along with stale generations kept for snapshots which did not outlive it.
+pub fn remove_interrupted(log_dir: &Path) -> error::Result<()> {
+    for entry in fs::read_dir(log_dir)? {
+        let path = entry?.path();
+        if path.extension() == Some("compacting".as_ref()) {
+            warn!("Removing interrupted compaction {}", path.display());
+            fs::remove_file(path)?;
+        } else if path.extension() == Some("stale".as_ref()) {
+            warn!("Removing stale generation {}", path.display());
+            fs::remove_file(path)?;
+        }
+    }
+    sync::sync_dir(log_dir)?;
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use std::io;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+
+use crate::error;
+use crate::KvsError;
+
+use super::writer::Writer;
+
+// A write to run with the writer locked.
+type Op = Box<dyn FnOnce(&mut Writer) -> error::Result<()> + Send>;
+
+/// A write waiting in the group commit queue.
+struct Request {
+    op: Op,
+    done: Sender<error::Result<()>>,
+}
+
+/// Commits concurrent writes in groups which share a single sync.
+///
+/// Every write is queued before its caller waits for the writer. Whoever gets
+/// the writer next becomes the group's leader: it runs every queued write in
+/// order, syncs the log once for all of them as the sync policy calls for,
+/// and then hands each caller its result. Callers whose writes were committed
+/// by another leader return as soon as they get the writer.
+#[derive(Default)]
+pub struct GroupCommit {
+    queue: Mutex<Vec<Request>>,
+}
+
+impl GroupCommit {
+    /// Queues a write and waits for it to be committed.
+    ///
+    /// A write which fails, or panics, does not affect the rest of its group,
+    /// but if the group's sync fails then so does every write in it.
+    pub fn commit(
+        &self,
+        writer: &Mutex<Writer>,
+        op: impl FnOnce(&mut Writer) -> error::Result<()> + Send + 'static,
+    ) -> error::Result<()> {
+        let (done, result) = mpsc::channel();
+        self.queue.lock().unwrap().push(Request {
+            op: Box::new(op),
+            done,
+        });
+
+        let mut writer = writer.lock().unwrap();
+        // A leader only lets go of the writer once it has sent every result.
+        if let Ok(res) = result.try_recv() {
+            return res;
+        }
+
+        let group = mem::take(&mut *self.queue.lock().unwrap());
+        trace!("Committing a group of {} writes", group.len());
+        let results: Vec<_> = group
+            .into_iter()
+            .map(|request| {
+                // Catch a panicking write, so that it fails on its own instead
+                // of poisoning the writer for every later write.
+                let op = request.op;
+                let res = panic::catch_unwind(AssertUnwindSafe(|| op(&mut writer)))
+                    .unwrap_or_else(|_| Err(KvsError::String(String::from("Write panicked"))));
+                (res, request.done)
+            })
+            .collect();
+        let synced = writer.sync_group();
+
+        for (res, done) in results {
+            let res = match (&synced, res) {
+                (Err(e), Ok(())) => Err(copy_error(e)),
+                (_, res) => res,
+            };
+            // The caller is blocked on the result, so it cannot have gone.
+            let _ = done.send(res);
+        }
+        drop(writer);
+        result.recv().expect("Group commit leader dropped a result")
+    }
+}
+
+/// Copies an error for every write in a group which failed to sync.
+fn copy_error(e: &KvsError) -> KvsError {
+    match e {
+        KvsError::Io(e) => KvsError::Io(io::Error::new(e.kind(), e.to_string())),
+        e => KvsError::String(e.to_string()),
+    }
+}
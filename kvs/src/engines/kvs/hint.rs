@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crc32fast::Hasher;
+
+use crate::error;
+use crate::{EncryptionKey, KvsError};
+
+use super::{EntryPos, Generation};
+
+/// The magic number and format version which start every hint file.
+const HEADER: [u8; 5] = *b"KVH\0\x01";
+
+/// The magic number and format version which start every encrypted hint
+/// file.
+const ENCRYPTED_HEADER: [u8; 5] = *b"KVH\0\x02";
+
+/// The size of a hint record's fixed-width prefix in bytes.
+const RECORD_PREFIX_SIZE: usize = 37;
+
+const PUT: u8 = 0;
+const DELETE: u8 = 1;
+
+/// The final state of a key within a single generation.
+#[derive(Clone, Copy, Debug)]
+pub enum Hint {
+    /// The key was set; the position points at its latest entry.
+    Put(EntryPos),
+    /// The key was removed; the position points at the tombstone.
+    Delete(EntryPos),
+}
+
+/// Every key touched by a generation, mapped to its final state.
+pub type Hints = BTreeMap<Vec<u8>, Hint>;
+
+/// Writes the hint file for a generation.
+///
+/// A hint file summarizes a log file so that it can be loaded without
+/// decoding every entry. It starts with a header and the length of the log
+/// it describes, followed by one record per key and a trailing CRC32 of
+/// everything before it. Each record holds the key's position and when its
+/// value expires, in milliseconds since the Unix epoch or `u64::MAX` if it
+/// never does.
+///
+/// If `key` is given, everything between the header and the CRC32 is sealed
+/// with it, since hints hold the keys of the store.
+///
+/// The file is written to a temporary path first and renamed into place, so
+/// a crash never leaves a partially written hint behind.
+pub fn write(
+    log_dir: &Path,
+    gen: Generation,
+    log_len: u64,
+    hints: &Hints,
+    key: Option<&EncryptionKey>,
+) -> error::Result<()> {
+    let mut byte_buf = log_len.to_le_bytes().to_vec();
+
+    for (key, hint) in hints {
+        let (kind, pos) = match hint {
+            Hint::Put(pos) => (PUT, pos),
+            Hint::Delete(pos) => (DELETE, pos),
+        };
+        byte_buf.push(kind);
+        byte_buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        byte_buf.extend_from_slice(&pos.gen.to_le_bytes());
+        byte_buf.extend_from_slice(&pos.pos.to_le_bytes());
+        byte_buf.extend_from_slice(&pos.len.to_le_bytes());
+        byte_buf.extend_from_slice(&pos.expires_at.unwrap_or(u64::MAX).to_le_bytes());
+        byte_buf.extend_from_slice(key);
+    }
+
+    let mut byte_buf = match key {
+        Some(key) => {
+            let mut sealed = ENCRYPTED_HEADER.to_vec();
+            sealed.extend_from_slice(&key.seal(&ENCRYPTED_HEADER, &byte_buf));
+            sealed
+        }
+        None => {
+            let mut plain = HEADER.to_vec();
+            plain.extend_from_slice(&byte_buf);
+            plain
+        }
+    };
+
+    let mut crc_hasher = Hasher::new();
+    crc_hasher.update(&byte_buf);
+    byte_buf.extend_from_slice(&crc_hasher.finalize().to_le_bytes());
+
+    let tmp_path = hint_path(log_dir, gen).with_extension("hint.tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&byte_buf)?;
+    file.sync_data()?;
+    fs::rename(&tmp_path, hint_path(log_dir, gen))?;
+
+    Ok(())
+}
+
+/// Reads the hint file for a generation.
+///
+/// Returns `None` if the generation has no hint file. A hint file which fails
+/// its checksum, cannot be decoded or unsealed with `key`, was written in
+/// another format, or describes a log of a different length than `log_len`
+/// results in an error.
+pub fn read(
+    log_dir: &Path,
+    gen: Generation,
+    log_len: u64,
+    key: Option<&EncryptionKey>,
+) -> error::Result<Option<Hints>> {
+    let mut byte_buf = vec![];
+    match File::open(hint_path(log_dir, gen)) {
+        Ok(mut file) => file.read_to_end(&mut byte_buf)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let encrypted = byte_buf.starts_with(&ENCRYPTED_HEADER);
+    if !encrypted && !byte_buf.starts_with(&HEADER) {
+        return Err(corrupt("hint file has an unknown format"));
+    }
+    if byte_buf.len() < HEADER.len() + 12 {
+        return Err(corrupt("hint file is truncated"));
+    }
+
+    let (body, crc_bytes) = byte_buf.split_at(byte_buf.len() - 4);
+    let mut crc_hasher = Hasher::new();
+    crc_hasher.update(body);
+    if crc_hasher.finalize() != u32::from_le_bytes(crc_bytes.try_into()?) {
+        return Err(corrupt("hint file checksum mismatch"));
+    }
+
+    let body = match (encrypted, key) {
+        (false, _) => body[HEADER.len()..].to_vec(),
+        (true, None) => return Err(corrupt("hint file is encrypted")),
+        (true, Some(key)) => key
+            .open(&ENCRYPTED_HEADER, &body[HEADER.len()..])
+            .ok_or_else(|| corrupt("hint file failed authentication"))?,
+    };
+    if body.len() < 8 {
+        return Err(corrupt("hint file is truncated"));
+    }
+    if u64::from_le_bytes(body[..8].try_into()?) != log_len {
+        return Err(corrupt("hint file does not match its log"));
+    }
+
+    let mut hints = BTreeMap::new();
+    let mut rest = &body[8..];
+    while !rest.is_empty() {
+        if rest.len() < RECORD_PREFIX_SIZE {
+            return Err(corrupt("hint record is truncated"));
+        }
+
+        let kind = rest[0];
+        let key_size = u32::from_le_bytes(rest[1..5].try_into()?) as usize;
+        let pos = EntryPos {
+            gen: u64::from_le_bytes(rest[5..13].try_into()?),
+            pos: u64::from_le_bytes(rest[13..21].try_into()?),
+            len: u64::from_le_bytes(rest[21..29].try_into()?),
+            seq: 0,
+            expires_at: match u64::from_le_bytes(rest[29..RECORD_PREFIX_SIZE].try_into()?) {
+                u64::MAX => None,
+                expires_at => Some(expires_at),
+            },
+        };
+        rest = &rest[RECORD_PREFIX_SIZE..];
+
+        if rest.len() < key_size {
+            return Err(corrupt("hint record is truncated"));
+        }
+        let key = rest[..key_size].to_vec();
+        rest = &rest[key_size..];
+
+        let hint = match kind {
+            PUT => Hint::Put(pos),
+            DELETE => Hint::Delete(pos),
+            _ => return Err(KvsError::Unexpectedcommandtype),
+        };
+        hints.insert(key, hint);
+    }
+
+    Ok(Some(hints))
+}
+
+/// Removes the hint file for a generation, if there is one.
+pub fn remove(log_dir: &Path, gen: Generation) -> error::Result<()> {
+    match fs::remove_file(hint_path(log_dir, gen)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => Ok(res?),
+    }
+}
+
+pub fn hint_path(log_dir: &Path, gen: Generation) -> PathBuf {
+    log_dir.join(format!("{}.hint", gen))
+}
+
+fn corrupt(msg: &str) -> KvsError {
+    KvsError::String(msg.to_owned())
+}
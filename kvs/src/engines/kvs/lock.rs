@@ -0,0 +1,71 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use fs2::FileExt;
+
+use crate::error;
+use crate::KvsError;
+
+/// The name of the lock file in a data directory.
+const LOCK_FILE: &str = "LOCK";
+
+/// How many times to look for the holder's process id before giving up.
+const PID_ATTEMPTS: usize = 100;
+
+/// An exclusive advisory lock on a data directory, held by the one store
+/// which may write to it.
+///
+/// The lock file holds the id of the process holding the lock, so that
+/// others can say who they are waiting for. The lock is released when it is
+/// dropped, or when its process exits; the file itself is left behind.
+#[derive(Debug)]
+pub struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Locks `log_dir`, failing with `KvsError::Locked` if another store
+    /// already holds its lock.
+    pub fn acquire(log_dir: &Path) -> error::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(lock_path(log_dir))?;
+
+        // The holder writes its id as soon as it has the lock, so an empty
+        // file only means it has not got round to it yet.
+        for _ in 0..PID_ATTEMPTS {
+            match file.try_lock_exclusive() {
+                Ok(()) => {
+                    file.set_len(0)?;
+                    file.seek(SeekFrom::Start(0))?;
+                    writeln!(file, "{}", process::id())?;
+                    file.sync_data()?;
+                    return Ok(Self { _file: file });
+                }
+                Err(ref e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                    let mut pid = String::new();
+                    file.seek(SeekFrom::Start(0))?;
+                    file.read_to_string(&mut pid)?;
+                    if let Ok(pid) = pid.trim().parse() {
+                        return Err(KvsError::Locked { pid });
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(fs2::lock_contended_error().into())
+    }
+}
+
+/// Returns the path of a data directory's lock file.
+pub fn lock_path(log_dir: &Path) -> PathBuf {
+    log_dir.join(LOCK_FILE)
+}
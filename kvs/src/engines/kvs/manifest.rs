@@ -0,0 +1,125 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+use crate::engines::sync;
+use crate::error;
+use crate::KvsError;
+
+/// The name of a manifest file.
+pub const MANIFEST_FILE: &str = "MANIFEST";
+
+/// The first line of a manifest, naming its format.
+const MANIFEST_HEADER: &str = "kvs manifest 1";
+
+/// The list of files which make up a checkpoint or a backup, so that it can
+/// be checked and restored without opening it.
+///
+/// A manifest is a text file with a header line followed by one line per
+/// file: its name, its length in bytes and its CRC32 in hexadecimal.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub files: Vec<ManifestFile>,
+}
+
+/// A file listed in a manifest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestFile {
+    pub name: String,
+    pub len: u64,
+    pub crc: u32,
+}
+
+impl ManifestFile {
+    /// Parses a file's line in a manifest.
+    pub fn parse(line: &str) -> error::Result<Self> {
+        let fields: Vec<_> = line.split(' ').collect();
+        let (name, len, crc) = match fields[..] {
+            [name, len, crc] => (name, len, crc),
+            _ => return Err(invalid_line(line)),
+        };
+        Ok(Self {
+            name: name.to_owned(),
+            len: len.parse().map_err(|_| invalid_line(line))?,
+            crc: u32::from_str_radix(crc, 16).map_err(|_| invalid_line(line))?,
+        })
+    }
+
+    /// Checksums the file at `path`, listing it under its file name.
+    pub fn of(path: &Path) -> error::Result<Self> {
+        let (len, crc) = checksum(File::open(path)?)?;
+        Ok(Self {
+            name: path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .expect("Data file names are ASCII")
+                .to_owned(),
+            len,
+            crc,
+        })
+    }
+}
+
+impl fmt::Display for ManifestFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {:08x}", self.name, self.len, self.crc)
+    }
+}
+
+impl Manifest {
+    /// Reads the manifest in `dir`.
+    pub fn read(dir: &Path) -> error::Result<Self> {
+        let file = File::open(dir.join(MANIFEST_FILE))?;
+        let mut lines = BufReader::new(file).lines();
+        match lines.next().transpose()? {
+            Some(ref header) if header == MANIFEST_HEADER => {}
+            header => return Err(invalid_line(&header.unwrap_or_default())),
+        }
+
+        let files = lines
+            .map(|line| ManifestFile::parse(&line?))
+            .collect::<error::Result<_>>()?;
+        Ok(Self { files })
+    }
+
+    /// Writes the manifest into `dir`, replacing any manifest already there.
+    ///
+    /// The manifest is written under a temporary name and renamed into
+    /// place, so a crash never leaves a partial one behind.
+    pub fn write(&self, dir: &Path) -> error::Result<()> {
+        let path = dir.join(MANIFEST_FILE);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        writeln!(file, "{}", MANIFEST_HEADER)?;
+        for manifest_file in &self.files {
+            writeln!(file, "{}", manifest_file)?;
+        }
+        file.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        sync::sync_dir(dir)?;
+        Ok(())
+    }
+}
+
+/// Returns the length and CRC32 of everything `reader` reads.
+pub fn checksum(mut reader: impl Read) -> io::Result<(u64, u32)> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = [0; 64 * 1024];
+    let mut len = 0;
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok((len, hasher.finalize())),
+            Ok(read) => {
+                hasher.update(&buf[..read]);
+                len += read as u64;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn invalid_line(line: &str) -> KvsError {
+    KvsError::String(format!("Invalid manifest line: {}", line))
+}
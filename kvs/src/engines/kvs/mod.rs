@@ -0,0 +1,1197 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::{Range, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use memmap2::Mmap;
+
+use crate::clock;
+use crate::engines::sync::{self, SyncState, Syncer};
+use crate::entry::{self, Entry, Record};
+use crate::error;
+use crate::{EncryptionKey, KvsError, WriteBatch};
+
+pub use self::backup::{backup, restore, BackupStats};
+pub use self::options::{CompactionTrigger, CorruptionPolicy, KvStoreOptions};
+pub use self::scan::KvStoreScan;
+pub use self::snapshot::Snapshot;
+pub use self::transaction::Transaction;
+pub use self::verify::{verify, CorruptRange, VerifyReport};
+
+use self::group::GroupCommit;
+use self::hint::{Hint, Hints};
+use self::lock::DirLock;
+use self::snapshot::Pins;
+use self::sweeper::Sweeper;
+use self::writer::Writer;
+use super::KvsEngine;
+
+mod backup;
+mod checkpoint;
+mod compaction;
+mod group;
+mod hint;
+mod lock;
+mod manifest;
+mod options;
+mod scan;
+mod snapshot;
+mod sweeper;
+mod transaction;
+mod verify;
+mod writer;
+
+/// The least amount of stale bytes which a ratio-triggered compaction will
+/// bother with.
+const MIN_COMPACTION_BYTES: u64 = 64 * 1024;
+
+/// How many times a read-only store tries to load logs which a writer keeps
+/// compacting away.
+const READ_ONLY_ATTEMPTS: usize = 3;
+
+type Generation = u64;
+type Readers = HashMap<Generation, Arc<LogReader>>;
+type KeyDir = BTreeMap<Vec<u8>, EntryPos>;
+// Keys which expired and were removed from the keydir behind the store's
+// back, waiting for the store to account for them.
+type Expired = Mutex<Vec<(Vec<u8>, EntryPos)>>;
+
+/// A key-value store which is backed by write-ahead logging.
+///
+/// Once enough of the log is stale, it is compacted on a background thread
+/// while reads and writes continue.
+///
+/// Keys set with a time to live stop being visible once they expire. They
+/// are removed from memory by a background sweep, or when they are next
+/// read, and dropped from the log by the next compaction.
+///
+/// A `KvStore` is a handle which can be cloned cheaply and shared between
+/// threads. Reads run concurrently, using positional reads on log files
+/// shared by every handle, while writes are serialized. Writes from
+/// concurrent threads are committed in groups which share a single sync, so
+/// a durable sync policy costs much less than one sync per write. The store
+/// is closed once the last handle is dropped.
+#[derive(Clone)]
+pub struct KvStore {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    log_dir: PathBuf,
+    options: KvStoreOptions,
+    readers: Arc<RwLock<Readers>>,
+    keydir: Arc<RwLock<KeyDir>>,
+    pins: Arc<Mutex<Pins>>,
+    expired: Arc<Expired>,
+    sweeper: Option<Sweeper>,
+    syncer: Option<Syncer>,
+    group: GroupCommit,
+    writer: Arc<Mutex<Writer>>,
+    // Released only once the writer has been closed.
+    _lock: Option<DirLock>,
+}
+
+impl KvStore {
+    /// Creates a new key-value store.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("foo", "bar");
+    /// ```
+    pub fn open(log_dir: impl Into<PathBuf>) -> error::Result<Self> {
+        Self::open_with(log_dir, KvStoreOptions::default())
+    }
+
+    /// Creates a new key-value store with the given options.
+    ///
+    /// Logs written in an older format are upgraded to the current one by a
+    /// compaction which starts as soon as the store is opened, unless it is
+    /// opened read-only.
+    ///
+    /// Only one store at a time may write to a data directory, which it locks
+    /// until it is closed. A read-only store takes no lock, so it can be
+    /// opened alongside a writer in this or another process; it sees the
+    /// store as it was when it was opened.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Locked` if another store is already writing to the
+    /// data directory, `KvsError::UnsupportedVersion` if any log was written
+    /// in a newer format than this crate understands, `KvsError::MissingKey`
+    /// if any log is encrypted but the options hold no key, and
+    /// `KvsError::WrongKey` if it was encrypted with another key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvStoreOptions, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let options = KvStoreOptions::new().data_dir("data");
+    ///
+    /// let store = KvStore::open_with(temp_dir.path(), options).unwrap();
+    /// store.set("foo", "bar").unwrap();
+    /// assert!(temp_dir.path().join("data").is_dir());
+    /// ```
+    pub fn open_with(log_dir: impl Into<PathBuf>, options: KvStoreOptions) -> error::Result<Self> {
+        let log_dir = log_dir.into().join(&options.data_dir);
+
+        if options.read_only {
+            // A writer may compact logs away while they are being loaded, in
+            // which case loading again finds the logs which replaced them.
+            for _ in 1..READ_ONLY_ATTEMPTS {
+                match Self::open_dir(log_dir.clone(), options.clone(), None) {
+                    Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {}
+                    res => return res,
+                }
+            }
+            return Self::open_dir(log_dir, options, None);
+        }
+
+        fs::create_dir_all(&log_dir)?;
+        let lock = DirLock::acquire(&log_dir)?;
+        compaction::remove_interrupted(&log_dir)?;
+        Self::open_dir(log_dir, options, Some(lock))
+    }
+
+    /// Loads the store in `log_dir`, which the caller has locked unless the
+    /// store is read-only.
+    fn open_dir(
+        log_dir: PathBuf,
+        options: KvStoreOptions,
+        lock: Option<DirLock>,
+    ) -> error::Result<Self> {
+        let mut keydir = BTreeMap::new();
+        let mut readers = HashMap::new();
+
+        let gen_list = sorted_gen_list(&log_dir)?;
+        let mut uncompacted = 0;
+        let now = clock::to_millis(options.clock.now());
+
+        for &gen in &gen_list {
+            let mut reader = LogReader::open(&log_dir, gen, options.encryption_key.as_ref())?;
+            let newest = Some(&gen) == gen_list.last();
+            let mut log_len = reader.file.metadata()?.len();
+
+            let hints = match hint::read(&log_dir, gen, log_len, reader.key.as_ref()) {
+                Ok(Some(hints)) => hints,
+                res => {
+                    if let Err(e) = res {
+                        warn!("Ignoring hint file for generation {}: {}", gen, e);
+                    }
+
+                    // Only the newest generation can have been cut short by a
+                    // crash, since every other one was sealed before it.
+                    let (hints, valid_len) = match load(gen, &reader, newest, &options) {
+                        Err(e @ KvsError::Corruption { .. })
+                            if options.corruption_policy == CorruptionPolicy::Quarantine =>
+                        {
+                            warn!("Quarantining generation {}: {}", gen, e);
+                            if !options.read_only {
+                                let path = log_path(&log_dir, gen);
+                                fs::rename(&path, path.with_extension("log.corrupt"))?;
+                                hint::remove(&log_dir, gen)?;
+                                sync::sync_dir(&log_dir)?;
+                            }
+                            continue;
+                        }
+                        res => res?,
+                    };
+                    if valid_len < log_len {
+                        warn!(
+                            "Dropping {} bytes of torn writes from the end of generation {}",
+                            log_len - valid_len,
+                            gen
+                        );
+                        if !options.read_only {
+                            OpenOptions::new()
+                                .write(true)
+                                .open(log_path(&log_dir, gen))?
+                                .set_len(valid_len)?;
+                        }
+                        log_len = valid_len;
+                    }
+
+                    if !options.read_only {
+                        hint::write(&log_dir, gen, log_len, &hints, reader.key.as_ref())?;
+                    }
+                    hints
+                }
+            };
+
+            uncompacted += apply_hints(hints, log_len - reader.data_start, &mut keydir, now);
+            // Every existing generation is sealed once the writer moves on to
+            // a new one, but a read-only store's newest one may still be
+            // written to by another.
+            if options.mmap_reads && !(options.read_only && newest) {
+                reader.map()?;
+            }
+            readers.insert(gen, Arc::new(reader));
+        }
+
+        let live = keydir.values().map(|entry_pos| entry_pos.len).sum();
+        let mut seq = 0;
+        for entry_pos in keydir.values_mut() {
+            seq += 1;
+            entry_pos.seq = seq;
+        }
+        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+        let writer = if options.read_only {
+            None
+        } else {
+            Some(new_log_file(&log_dir, current_gen, &mut readers, &options)?)
+        };
+
+        let outdated = readers
+            .values()
+            .filter(|reader| reader.is_outdated(&options))
+            .count();
+
+        let readers = Arc::new(RwLock::new(readers));
+        let keydir = Arc::new(RwLock::new(keydir));
+        let pins = Arc::new(Mutex::new(Pins::default()));
+        let expired = Arc::new(Mutex::new(vec![]));
+        let sweeper = options.sweep_interval.map(|interval| {
+            Sweeper::spawn(
+                Arc::clone(&keydir),
+                Arc::clone(&expired),
+                Arc::clone(&options.clock),
+                interval,
+            )
+        });
+
+        let mut writer = Writer {
+            log_dir: log_dir.clone(),
+            options: options.clone(),
+            readers: Arc::clone(&readers),
+            keydir: Arc::clone(&keydir),
+            pins: Arc::clone(&pins),
+            expired: Arc::clone(&expired),
+            writer,
+            sync: SyncState::new(options.sync_policy),
+            needs_sync: false,
+            removed: HashMap::new(),
+            current_gen,
+            seq,
+            live,
+            uncompacted,
+            compaction: None,
+        };
+
+        // Compaction rewrites every live entry in the current format, which
+        // upgrades logs written by older versions of this crate and encrypts
+        // logs written before the store had a key.
+        if outdated > 0 && !options.read_only {
+            info!(
+                "Upgrading {} log files to format version {}",
+                outdated,
+                entry::CURRENT_VERSION
+            );
+            writer.compact()?;
+        }
+
+        let writer = Arc::new(Mutex::new(writer));
+        let syncer = match options.sync_policy.interval() {
+            Some(interval) if !options.read_only => {
+                let writer = Arc::clone(&writer);
+                Some(Syncer::spawn("kvs-syncer", interval, move || {
+                    if let Err(e) = writer.lock().unwrap().sync_pending() {
+                        error!("Failed to sync log: {}", e);
+                    }
+                }))
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                log_dir,
+                options,
+                readers,
+                keydir,
+                pins,
+                expired,
+                sweeper,
+                syncer,
+                group: GroupCommit::default(),
+                writer,
+                _lock: lock,
+            }),
+        })
+    }
+
+    /// Starts an optimistic transaction.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("counter", "1").unwrap();
+    ///
+    /// let mut txn = store.begin();
+    /// let counter = txn.get("counter").unwrap().unwrap();
+    /// let counter: u64 = String::from_utf8(counter).unwrap().parse().unwrap();
+    /// txn.set("counter", (counter + 1).to_string());
+    /// txn.commit().unwrap();
+    ///
+    /// assert_eq!(store.get("counter").unwrap(), Some(String::from("2")));
+    /// ```
+    pub fn begin(&self) -> Transaction {
+        Transaction::new(self.clone())
+    }
+
+    /// Takes a snapshot of the store for consistent reads.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("foo", "bar").unwrap();
+    ///
+    /// let mut snapshot = store.snapshot();
+    /// store.set("foo", "baz").unwrap();
+    ///
+    /// assert_eq!(snapshot.get("foo").unwrap(), Some(b"bar".to_vec()));
+    /// ```
+    pub fn snapshot(&self) -> Snapshot {
+        // The writer is locked so that the sequence number matches the keydir.
+        let writer = self.inner.writer.lock().unwrap();
+        let keydir = self.inner.keydir.read().unwrap();
+        Snapshot::new(
+            &self.inner.log_dir,
+            keydir.clone(),
+            writer.seq,
+            Arc::clone(&self.inner.pins),
+            Arc::clone(&self.inner.options.clock),
+            self.inner.options.encryption_key.clone(),
+        )
+    }
+
+    /// Writes a consistent copy of the store into `dest_dir`, which can be
+    /// opened with `KvStore::open` or `KvStore::open_with` like the store's
+    /// own path.
+    ///
+    /// The active generation is sealed, and every generation up to it is
+    /// hard-linked into the checkpoint's data directory, or copied if it is on
+    /// another filesystem, along with the hint files and a manifest listing
+    /// every file with its checksum. Writes only wait while the generation is
+    /// sealed, and compaction carries on while the files are linked.
+    ///
+    /// The checkpoint's data directory must be empty. An encrypted store's
+    /// checkpoint can only be opened with the same key.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::ReadOnly` if the store was opened read-only.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("foo", "bar").unwrap();
+    ///
+    /// let backup_dir = TempDir::new().unwrap();
+    /// store.checkpoint(backup_dir.path()).unwrap();
+    /// store.set("foo", "baz").unwrap();
+    ///
+    /// let backup = KvStore::open(backup_dir.path()).unwrap();
+    /// assert_eq!(backup.get("foo").unwrap(), Some(String::from("bar")));
+    /// ```
+    pub fn checkpoint(&self, dest_dir: impl AsRef<Path>) -> error::Result<()> {
+        let dest = dest_dir.as_ref().join(&self.inner.options.data_dir);
+        let log_dir = &self.inner.log_dir;
+
+        // Compaction retires generations with the pins locked, so every
+        // generation listed here stays readable until it is released.
+        let gens: Vec<_> = {
+            let mut writer = self.inner.writer.lock().unwrap();
+            let sealed_gen = writer.seal()?;
+            let mut pins = self.inner.pins.lock().unwrap();
+            let gens: Vec<_> = sorted_gen_list(log_dir)?
+                .into_iter()
+                .filter(|&gen| gen <= sealed_gen)
+                .collect();
+            pins.pin(gens.iter().copied().collect());
+            gens
+        };
+
+        let res = checkpoint::checkpoint(log_dir, &gens, &dest);
+        let mut pins = self.inner.pins.lock().unwrap();
+        pins.release(log_dir, gens.into_iter().collect());
+        res
+    }
+
+    /// Waits for the background compaction to finish, if one is running.
+    ///
+    /// Writes never wait for a compaction, so this is only needed to look at
+    /// the data directory once the compacted generations have replaced the
+    /// stale ones.
+    pub fn wait_for_compaction(&self) {
+        self.inner.writer.lock().unwrap().finish_compaction(true);
+    }
+
+    /// Reads the value of a key along with the sequence number of the write
+    /// which set it.
+    ///
+    /// An expired key is removed from the keydir and reads as missing.
+    fn read(&self, key: &[u8]) -> error::Result<Option<(u64, Vec<u8>)>> {
+        let now = self.now();
+        let keydir = self.inner.keydir.read().unwrap();
+        let entry_pos = match keydir.get(key) {
+            Some(&entry_pos) => entry_pos,
+            None => return Ok(None),
+        };
+        if entry_pos.is_expired(now) {
+            drop(keydir);
+            self.expire(key, entry_pos);
+            return Ok(None);
+        }
+
+        // The reader is opened while the keydir is locked, so that a
+        // compaction cannot delete the generation in between.
+        let reader = self.reader(entry_pos.gen)?;
+        drop(keydir);
+
+        let value = reader.read(entry_pos)?.value.unwrap_or_default();
+        Ok(Some((entry_pos.seq, value)))
+    }
+
+    /// Removes an expired key from the keydir, unless it has been written to
+    /// since it was found to have expired.
+    fn expire(&self, key: &[u8], entry_pos: EntryPos) {
+        let mut keydir = self.inner.keydir.write().unwrap();
+        if keydir.get(key) == Some(&entry_pos) {
+            keydir.remove(key);
+            self.inner
+                .expired
+                .lock()
+                .unwrap()
+                .push((key.to_vec(), entry_pos));
+        }
+    }
+
+    /// Returns the shared reader of a generation, opening it if need be.
+    fn reader(&self, gen: Generation) -> error::Result<Arc<LogReader>> {
+        if let Some(reader) = self.inner.readers.read().unwrap().get(&gen) {
+            return Ok(Arc::clone(reader));
+        }
+
+        // Only sealed generations are opened here; the active one's reader is
+        // opened along with its log.
+        let reader = Arc::new(LogReader::open_sealed(
+            &self.inner.log_dir,
+            gen,
+            &self.inner.options,
+        )?);
+        let mut readers = self.inner.readers.write().unwrap();
+        Ok(Arc::clone(readers.entry(gen).or_insert(reader)))
+    }
+
+    /// Runs a write through the group commit, waiting until it is durable as
+    /// far as the sync policy asks.
+    ///
+    /// The write runs with the writer locked, so it can read the store and
+    /// write to it atomically.
+    fn commit(
+        &self,
+        op: impl FnOnce(&mut Writer) -> error::Result<()> + Send + 'static,
+    ) -> error::Result<()> {
+        self.inner.group.commit(&self.inner.writer, op)
+    }
+
+    /// Returns the current time, in milliseconds since the Unix epoch.
+    fn now(&self) -> u64 {
+        clock::to_millis(self.inner.options.clock.now())
+    }
+
+    /// Returns the position of a key's entry, unless it is missing or has
+    /// expired.
+    fn live_entry(&self, key: &[u8]) -> Option<EntryPos> {
+        let now = self.now();
+        self.inner
+            .keydir
+            .read()
+            .unwrap()
+            .get(key)
+            .filter(|entry_pos| !entry_pos.is_expired(now))
+            .copied()
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // The sweeper is stopped before the writer is dropped, so that the
+        // writer's final hints account for every key it swept.
+        if let Some(sweeper) = self.sweeper.take() {
+            sweeper.stop();
+        }
+        // The syncer shares the writer, which is only closed once the syncer
+        // lets go of it.
+        drop(self.syncer.take());
+    }
+}
+
+impl KvsEngine for KvStore {
+    type Scan = KvStoreScan;
+
+    /// Sets a key-value pair in the store.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set_bytes(&b"foo"[..], &b"\xff"[..]).unwrap();
+    ///
+    /// let value = store.get_bytes(&b"foo"[..]).unwrap();
+    /// assert_eq!(value, Some(b"\xff".to_vec()));
+    /// ```
+    fn set_bytes(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> error::Result<()> {
+        let entry = Entry::set(key, value);
+        self.commit(move |writer| writer.write(entry))
+    }
+
+    /// Returns the value corresponding to the key. If the key doesn't exist,
+    /// the returns `None`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set_bytes(&b"foo"[..], &b"bar"[..]).unwrap();
+    ///
+    /// let value = store.get_bytes(&b"foo"[..]).unwrap();
+    /// assert_eq!(value, Some(b"bar".to_vec()));
+    ///
+    /// let value = store.get_bytes(&b"baz"[..]).unwrap();
+    /// assert_eq!(value, None);
+    /// ```
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> error::Result<Option<Vec<u8>>> {
+        Ok(self.read(&key.into())?.map(|(_, value)| value))
+    }
+
+    /// Removes a key from the store.
+    ///
+    /// # Errors
+    ///
+    /// Trying to remove a nonexistent will result in a
+    /// [`KvsError::KeyNotFound`] error.
+    ///
+    /// [`KvsError::KeyNotFound`]: enum.KvsError.html#variant.KeyNotFound
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set_bytes(&b"foo"[..], &b"bar"[..]).unwrap();
+    /// store.remove_bytes(&b"foo"[..]).unwrap();
+    ///
+    /// let value = store.get_bytes(&b"foo"[..]).unwrap();
+    /// assert_eq!(value, None);
+    /// ```
+    fn remove_bytes(&self, key: impl Into<Vec<u8>>) -> error::Result<()> {
+        let key = key.into();
+        let store = self.clone();
+        self.commit(move |writer| {
+            if store.live_entry(&key).is_some() {
+                writer.write(Entry::remove(key))
+            } else {
+                Err(KvsError::KeyNotFound)
+            }
+        })
+    }
+
+    /// Atomically sets a key to `new`, provided its current value is
+    /// `expected`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvsEngine, KvsError};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("counter", "1").unwrap();
+    ///
+    /// store
+    ///     .compare_and_swap("counter", Some("1".to_owned()), Some("2".to_owned()))
+    ///     .unwrap();
+    /// let res = store.compare_and_swap("counter", Some("1".to_owned()), None);
+    /// assert!(matches!(res, Err(KvsError::CompareAndSwap { .. })));
+    /// ```
+    fn compare_and_swap_bytes(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> error::Result<()> {
+        let key = key.into();
+        let store = self.clone();
+        // Holding the writer keeps other writes out, so the read and the
+        // write are atomic together.
+        self.commit(move |writer| {
+            let current = store.read(&key)?.map(|(_, value)| value);
+            if current != expected {
+                return Err(KvsError::CompareAndSwap { current });
+            }
+
+            match (new, current) {
+                (Some(new), _) => writer.write(Entry::set(key, new)),
+                (None, Some(_)) => writer.write(Entry::remove(key)),
+                (None, None) => Ok(()),
+            }
+        })
+    }
+
+    /// Atomically sets the value of a key, provided it already exists.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvsEngine, KvsError};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    ///
+    /// let res = store.set_if_present("foo", "bar");
+    /// assert!(matches!(res, Err(KvsError::KeyNotFound)));
+    /// store.set("foo", "bar").unwrap();
+    /// store.set_if_present("foo", "baz").unwrap();
+    /// ```
+    fn set_if_present_bytes(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> error::Result<()> {
+        let entry = Entry::set(key, value);
+        let store = self.clone();
+        self.commit(move |writer| {
+            if store.live_entry(&entry.key).is_some() {
+                writer.write(entry)
+            } else {
+                Err(KvsError::KeyNotFound)
+            }
+        })
+    }
+
+    /// Applies every operation in a batch, or none of them.
+    ///
+    /// The batch is appended to the log as a single record with one checksum,
+    /// so after a crash it is replayed either in full or not at all.
+    fn write_batch(&self, batch: WriteBatch) -> error::Result<()> {
+        self.commit(move |writer| writer.write_batch(batch.entries))
+    }
+
+    /// Scans the key-value pairs whose keys fall within a range.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("a", "1").unwrap();
+    /// store.set("b", "2").unwrap();
+    /// store.set("c", "3").unwrap();
+    ///
+    /// let keys: Vec<Vec<u8>> = store
+    ///     .scan(b"b".to_vec()..)
+    ///     .unwrap()
+    ///     .rev()
+    ///     .map(|pair| pair.unwrap().0)
+    ///     .collect();
+    /// assert_eq!(keys, vec![b"c".to_vec(), b"b".to_vec()]);
+    /// ```
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> error::Result<KvStoreScan> {
+        let now = self.now();
+        let keydir = self.inner.keydir.read().unwrap();
+        KvStoreScan::new(&keydir, range, now, |gen| self.reader(gen))
+    }
+
+    /// Sets a key-value pair in the store which expires after `ttl`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use kvs::{KvStore, KvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set_with_ttl("session", "abc", Duration::from_secs(60)).unwrap();
+    ///
+    /// let ttl = store.ttl("session").unwrap().unwrap();
+    /// assert!(ttl <= Duration::from_secs(60));
+    /// ```
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> error::Result<()> {
+        let entry = match clock::expiry(self.inner.options.clock.now(), ttl) {
+            Some(expires_at) => Entry::set_expiring(key, value, expires_at),
+            None => Entry::set(key, value),
+        };
+        self.commit(move |writer| writer.write(entry))
+    }
+
+    /// Makes an existing key expire at a point in time, by appending its
+    /// value again with the new expiry.
+    fn expire_at(&self, key: impl Into<Vec<u8>>, at: SystemTime) -> error::Result<()> {
+        let key = key.into();
+        let store = self.clone();
+        self.commit(move |writer| {
+            let (_, value) = store.read(&key)?.ok_or(KvsError::KeyNotFound)?;
+            writer.write(Entry::set_expiring(key, value, clock::to_millis(at)))
+        })
+    }
+
+    fn ttl(&self, key: impl Into<Vec<u8>>) -> error::Result<Option<Duration>> {
+        let entry_pos = self.live_entry(&key.into()).ok_or(KvsError::KeyNotFound)?;
+        Ok(clock::remaining(entry_pos.expires_at, self.now()))
+    }
+}
+
+fn new_log_file(
+    log_dir: &Path,
+    gen: Generation,
+    readers: &mut Readers,
+    options: &KvStoreOptions,
+) -> error::Result<BufWriterWithPos<File>> {
+    let mut writer = BufWriterWithPos::with_capacity(
+        options.write_buffer_size,
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path(log_dir, gen))?,
+    )?;
+    entry::write_header(&mut writer, gen, options.encryption_key.as_ref())?;
+    writer.sync()?;
+    sync::sync_dir(log_dir)?;
+
+    readers.insert(
+        gen,
+        Arc::new(LogReader::open(
+            log_dir,
+            gen,
+            options.encryption_key.as_ref(),
+        )?),
+    );
+    Ok(writer)
+}
+
+fn sorted_gen_list(log_dir: &Path) -> error::Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = fs::read_dir(log_dir)?
+        .flat_map(|res| -> error::Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .map(|s| s.trim_end_matches(".log"))
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    gen_list.sort_unstable();
+    Ok(gen_list)
+}
+
+/// Replays a log file, returning the final state of every key it touches
+/// along with the length of the log which holds complete entries.
+///
+/// If `recover` is set, an entry at the end of the log which is incomplete or
+/// fails its checksum is treated as a torn write: the replay stops before it
+/// rather than failing. Any other corrupt entry is skipped or fails the replay
+/// according to the options' corruption policy.
+fn load(
+    gen: Generation,
+    log_reader: &LogReader,
+    recover: bool,
+    options: &KvStoreOptions,
+) -> error::Result<(Hints, u64)> {
+    let version = log_reader.version;
+    let key = log_reader.key.as_ref();
+    let corruption_policy = options.corruption_policy;
+    let log_len = log_reader.file.metadata()?.len();
+    let reader = &mut BufReaderWithPos::with_capacity(options.read_buffer_size, &log_reader.file)?;
+    let mut pos = reader.seek(SeekFrom::Start(log_reader.data_start))?;
+    let mut hints = Hints::new();
+
+    while pos < log_len {
+        let record = match entry::record_from_reader(reader, version, gen, pos, key) {
+            Ok(record) => record,
+            Err(KvsError::Io(ref e)) if recover && e.kind() == io::ErrorKind::UnexpectedEof => {
+                break;
+            }
+            Err(KvsError::Corruption { .. }) if recover && reader.pos == log_len => break,
+            Err(e @ KvsError::Corruption { .. }) if corruption_policy == CorruptionPolicy::Skip => {
+                warn!("Skipping corrupt entry: {}", e);
+                pos = reader.pos;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let entries = match record {
+            Record::Entry(entry) => vec![(0..reader.pos - pos, entry)],
+            Record::Batch(entries) => entries,
+        };
+
+        for (range, entry) in entries {
+            let entry_pos = EntryPos {
+                expires_at: entry.expires_at,
+                ..(gen, pos + range.start..pos + range.end).into()
+            };
+            match entry.value {
+                Some(_) => hints.insert(entry.key, Hint::Put(entry_pos)),
+                None => hints.insert(entry.key, Hint::Delete(entry_pos)),
+            };
+        }
+
+        pos = reader.pos;
+    }
+
+    Ok((hints, pos))
+}
+
+/// Applies a generation's hints to the keydir, returning the number of bytes
+/// they make stale.
+///
+/// Every entry in the log other than the final value of each key is stale,
+/// as are any older entries the hints supersede. `data_len` is the length of
+/// the log's entries, excluding its header. A value which expired by `now`
+/// supersedes older entries like a removal does.
+fn apply_hints(hints: Hints, data_len: u64, keydir: &mut KeyDir, now: u64) -> u64 {
+    let mut uncompacted = data_len;
+
+    for (key, hint) in hints {
+        let old_entry = match hint {
+            Hint::Put(entry_pos) if !entry_pos.is_expired(now) => {
+                uncompacted -= entry_pos.len;
+                keydir.insert(key, entry_pos)
+            }
+            Hint::Put(_) | Hint::Delete(_) => keydir.remove(&key),
+        };
+
+        if let Some(old_entry) = old_entry {
+            uncompacted += old_entry.len;
+        }
+    }
+
+    uncompacted
+}
+
+fn log_path(log_dir: &Path, gen: Generation) -> PathBuf {
+    log_dir.join(format!("{}.log", gen))
+}
+
+/// Returns the path a compacted generation is kept at while a snapshot still
+/// refers to it.
+///
+/// Stale logs are moved out of the way so that a crash cannot bring their
+/// entries back to life when the store is next opened.
+fn stale_path(log_dir: &Path, gen: Generation) -> PathBuf {
+    log_path(log_dir, gen).with_extension("log.stale")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct EntryPos {
+    gen: Generation,
+    pos: u64,
+    len: u64,
+    // Identifies the write which put the entry in the keydir, and survives
+    // compaction moving the entry. Sequence numbers are only kept in memory;
+    // positions read back from the log are numbered afresh on open.
+    seq: u64,
+    // When the entry expires, in milliseconds since the Unix epoch.
+    expires_at: Option<u64>,
+}
+
+impl EntryPos {
+    fn is_expired(&self, now: u64) -> bool {
+        clock::is_expired(self.expires_at, now)
+    }
+}
+
+impl From<(Generation, Range<u64>)> for EntryPos {
+    fn from((gen, range): (Generation, Range<u64>)) -> Self {
+        Self {
+            gen,
+            pos: range.start,
+            len: range.end - range.start,
+            seq: 0,
+            expires_at: None,
+        }
+    }
+}
+
+/// A reader of a single generation's log.
+///
+/// Entries are read with positional reads, which leave the file's cursor
+/// alone, so a single reader can be shared by any number of threads. A
+/// sealed generation can also be memory-mapped, so that entries are decoded
+/// straight from the mapping without a system call.
+#[derive(Debug)]
+struct LogReader {
+    file: File,
+    // The mapped log, if it is sealed and the options ask for it. Entries past
+    // its end are read from the file.
+    map: Option<Mmap>,
+    version: entry::Version,
+    // The offset of the first entry, past the header.
+    data_start: u64,
+    // The key the log is encrypted with, if it is encrypted.
+    key: Option<EncryptionKey>,
+}
+
+impl LogReader {
+    /// Opens the log of a generation.
+    ///
+    /// A generation which has been compacted away while a snapshot refers to
+    /// it is read from its stale path instead.
+    ///
+    /// An encrypted log can only be opened with the key it was encrypted
+    /// with; `key` is ignored for logs which are not encrypted.
+    fn open(log_dir: &Path, gen: Generation, key: Option<&EncryptionKey>) -> error::Result<Self> {
+        let file = match File::open(log_path(log_dir, gen)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                File::open(stale_path(log_dir, gen))?
+            }
+            res => res?,
+        };
+        let mut reader = BufReaderWithPos::with_capacity(entry::HEADER_SIZE, &file)?;
+        let header = entry::read_header(&mut reader)?;
+        if let Some(header_gen) = header.gen.filter(|&header_gen| header_gen != gen) {
+            return Err(KvsError::String(format!(
+                "Log file for generation {} has a header for generation {}",
+                gen, header_gen
+            )));
+        }
+        let data_start = reader.pos;
+
+        let key = match (header.key_check, key) {
+            (None, _) => None,
+            (Some(_), None) => return Err(KvsError::MissingKey { gen }),
+            (Some(check), Some(key)) if !key.verify(&check) => {
+                return Err(KvsError::WrongKey { gen })
+            }
+            (Some(_), Some(key)) => Some(key.clone()),
+        };
+
+        Ok(LogReader {
+            file,
+            map: None,
+            version: header.version,
+            data_start,
+            key,
+        })
+    }
+
+    /// Opens the log of a sealed generation, which nothing appends to or
+    /// truncates any more, mapping it into memory if the options ask for it.
+    fn open_sealed(
+        log_dir: &Path,
+        gen: Generation,
+        options: &KvStoreOptions,
+    ) -> error::Result<Self> {
+        let mut reader = LogReader::open(log_dir, gen, options.encryption_key.as_ref())?;
+        if options.mmap_reads {
+            reader.map()?;
+        }
+        Ok(reader)
+    }
+
+    /// Maps the log into memory.
+    ///
+    /// The log must be sealed: mapping is only safe as long as the file is
+    /// not truncated, and entries appended after it is mapped are read from
+    /// the file instead.
+    fn map(&mut self) -> io::Result<()> {
+        if self.file.metadata()?.len() > 0 {
+            self.map = Some(unsafe { Mmap::map(&self.file)? });
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if the log should be rewritten by a compaction, either
+    /// because it is in an older format or because the store should be
+    /// encrypted and the log is not.
+    fn is_outdated(&self, options: &KvStoreOptions) -> bool {
+        self.version < entry::CURRENT_VERSION
+            || (self.key.is_none() && options.encryption_key.is_some())
+    }
+
+    /// Returns the mapped bytes of the entry at the given position, if the log
+    /// is mapped as far as the end of the entry.
+    fn mapped_bytes(&self, entry_pos: EntryPos) -> Option<&[u8]> {
+        let start = usize::try_from(entry_pos.pos).ok()?;
+        let end = start.checked_add(usize::try_from(entry_pos.len).ok()?)?;
+        self.map.as_ref()?.get(start..end)
+    }
+
+    /// Reads the raw bytes of the entry at the given position.
+    fn read_bytes(&self, entry_pos: EntryPos) -> error::Result<Vec<u8>> {
+        if let Some(bytes) = self.mapped_bytes(entry_pos) {
+            return Ok(bytes.to_vec());
+        }
+        let mut bytes = vec![0; entry_pos.len as usize];
+        read_exact_at(&self.file, &mut bytes, entry_pos.pos)?;
+        Ok(bytes)
+    }
+
+    /// Reads the entry at the given position.
+    fn read(&self, entry_pos: EntryPos) -> error::Result<Entry> {
+        let decode = |mut bytes: &[u8]| {
+            entry::from_reader(
+                &mut bytes,
+                self.version,
+                entry_pos.gen,
+                entry_pos.pos,
+                self.key.as_ref(),
+            )
+        };
+        match self.mapped_bytes(entry_pos) {
+            Some(bytes) => decode(bytes),
+            None => decode(&self.read_bytes(entry_pos)?),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(len) => {
+                buf = &mut buf[len..];
+                offset += len as u64;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct BufReaderWithPos<R: Read + Seek> {
+    reader: BufReader<R>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> BufReaderWithPos<R> {
+    fn with_capacity(capacity: usize, mut inner: R) -> error::Result<Self> {
+        let pos = inner.stream_position()?;
+        Ok(BufReaderWithPos {
+            reader: BufReader::with_capacity(capacity, inner),
+            pos,
+        })
+    }
+}
+
+impl<R: Read + Seek> Read for BufReaderWithPos<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.reader.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+#[derive(Debug)]
+struct BufWriterWithPos<W: Write + Seek> {
+    writer: BufWriter<W>,
+    pos: u64,
+}
+
+impl<W: Write + Seek> BufWriterWithPos<W> {
+    fn with_capacity(capacity: usize, mut inner: W) -> error::Result<Self> {
+        let pos = inner.stream_position()?;
+        Ok(BufWriterWithPos {
+            writer: BufWriter::with_capacity(capacity, inner),
+            pos,
+        })
+    }
+}
+
+impl BufWriterWithPos<File> {
+    /// Flushes the buffer and syncs the file's contents to disk.
+    fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+}
+
+impl<W: Write + Seek> Write for BufWriterWithPos<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.writer.seek(pos)?;
+        Ok(self.pos)
+    }
+}
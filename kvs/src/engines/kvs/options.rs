@@ -0,0 +1,225 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{Clock, Codec, EncryptionKey, SyncPolicy, SystemClock};
+
+/// Decides when a `KvStore` compacts its log.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompactionTrigger {
+    /// Compact once this many bytes of the log are stale.
+    Bytes(u64),
+    /// Compact once the ratio of stale to live bytes exceeds this value.
+    ///
+    /// Logs with less than 64 KiB of stale bytes are never compacted, so that
+    /// small stores do not compact on every write.
+    Ratio(f64),
+}
+
+/// Decides what `KvStore::open` does with a corrupt entry in the log.
+///
+/// A corrupt entry at the very end of the newest log is always treated as a
+/// torn write and truncated away, whatever the policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CorruptionPolicy {
+    /// Refuse to open the store, returning `KvsError::Corruption`.
+    Fail,
+    /// Skip the corrupt entry and keep loading the rest of its log.
+    Skip,
+    /// Set aside the whole log holding the corrupt entry, renaming it to
+    /// `<gen>.log.corrupt`, and open the store without it.
+    Quarantine,
+}
+
+/// Options for opening a `KvStore`.
+///
+/// # Examples
+///
+/// ```
+/// use kvs::{CompactionTrigger, KvStore, KvStoreOptions, SyncPolicy};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().unwrap();
+/// let options = KvStoreOptions::new()
+///     .compaction_trigger(CompactionTrigger::Ratio(1.5))
+///     .max_file_size(64 * 1024 * 1024)
+///     .sync_policy(SyncPolicy::Always);
+///
+/// let store = KvStore::open_with(temp_dir.path(), options).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub(super) data_dir: PathBuf,
+    pub(super) compaction_trigger: CompactionTrigger,
+    pub(super) max_file_size: u64,
+    pub(super) sync_policy: SyncPolicy,
+    pub(super) read_only: bool,
+    pub(super) corruption_policy: CorruptionPolicy,
+    pub(super) read_buffer_size: usize,
+    pub(super) write_buffer_size: usize,
+    pub(super) mmap_reads: bool,
+    pub(super) compression: Codec,
+    pub(super) compression_threshold: usize,
+    pub(super) encryption_key: Option<EncryptionKey>,
+    pub(super) clock: Arc<dyn Clock>,
+    pub(super) sweep_interval: Option<Duration>,
+}
+
+impl KvStoreOptions {
+    /// Creates the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the subdirectory of the store's path which holds its data.
+    ///
+    /// Defaults to `.kvsdata`.
+    pub fn data_dir(mut self, data_dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = data_dir.into();
+        self
+    }
+
+    /// Sets when the log is compacted.
+    ///
+    /// Defaults to once 1 MiB of the log is stale.
+    pub fn compaction_trigger(mut self, compaction_trigger: CompactionTrigger) -> Self {
+        self.compaction_trigger = compaction_trigger;
+        self
+    }
+
+    /// Sets the size in bytes at which a log file is closed and the next
+    /// generation started, both by the writer and by compaction.
+    ///
+    /// Files are only checked after each write, so one may go over by the
+    /// size of a single entry or batch.
+    ///
+    /// Defaults to 64 MiB.
+    pub fn max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// Sets when writes are synced to disk.
+    ///
+    /// The data directory itself is synced whenever log files are created or
+    /// removed, whatever the policy.
+    ///
+    /// Defaults to `SyncPolicy::Never`.
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Opens the store read-only.
+    ///
+    /// A read-only store never modifies its data directory; writes to it fail
+    /// with `KvsError::ReadOnly`. It takes no lock on the directory, so it can
+    /// be opened while another store is writing to it.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets what opening the store does with corrupt log entries.
+    ///
+    /// Defaults to `CorruptionPolicy::Fail`.
+    pub fn corruption_policy(mut self, corruption_policy: CorruptionPolicy) -> Self {
+        self.corruption_policy = corruption_policy;
+        self
+    }
+
+    /// Sets the buffer size in bytes used when replaying logs on open.
+    pub fn read_buffer_size(mut self, read_buffer_size: usize) -> Self {
+        self.read_buffer_size = read_buffer_size;
+        self
+    }
+
+    /// Sets the buffer size in bytes of the log writer.
+    pub fn write_buffer_size(mut self, write_buffer_size: usize) -> Self {
+        self.write_buffer_size = write_buffer_size;
+        self
+    }
+
+    /// Memory-maps sealed generations of the log, so that reads from them
+    /// decode entries straight from memory instead of reading the file.
+    ///
+    /// The active generation is always read from its file. Generations are
+    /// mapped as the writer moves on from them, and as compaction writes new
+    /// ones.
+    ///
+    /// Defaults to `false`.
+    pub fn mmap_reads(mut self, mmap_reads: bool) -> Self {
+        self.mmap_reads = mmap_reads;
+        self
+    }
+
+    /// Sets the codec which values are compressed with as they are written.
+    ///
+    /// Entries already in the log keep their codec, and every codec can be
+    /// read whatever this is set to.
+    ///
+    /// Defaults to `Codec::None`.
+    pub fn compression(mut self, compression: Codec) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the size in bytes below which values are stored uncompressed.
+    ///
+    /// Defaults to 128 bytes.
+    pub fn compression_threshold(mut self, compression_threshold: usize) -> Self {
+        self.compression_threshold = compression_threshold;
+        self
+    }
+
+    /// Encrypts the store's logs and hint files with `key`.
+    ///
+    /// An encrypted store can only be opened with the key it was encrypted
+    /// with. Opening an unencrypted store with a key encrypts its existing
+    /// logs with a compaction which starts as soon as it is opened.
+    ///
+    /// Defaults to no encryption.
+    pub fn encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Sets the clock which decides when keys expire.
+    ///
+    /// Defaults to `SystemClock`.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Sets how often expired keys are swept from the store in the
+    /// background, or `None` to only drop them when they are next read or
+    /// compacted.
+    ///
+    /// Defaults to every second.
+    pub fn sweep_interval(mut self, sweep_interval: Option<Duration>) -> Self {
+        self.sweep_interval = sweep_interval;
+        self
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            data_dir: PathBuf::from(".kvsdata"),
+            compaction_trigger: CompactionTrigger::Bytes(1024 * 1024),
+            max_file_size: 64 * 1024 * 1024,
+            sync_policy: SyncPolicy::Never,
+            read_only: false,
+            corruption_policy: CorruptionPolicy::Fail,
+            read_buffer_size: 8 * 1024,
+            write_buffer_size: 8 * 1024,
+            mmap_reads: false,
+            compression: Codec::None,
+            compression_threshold: 128,
+            encryption_key: None,
+            clock: Arc::new(SystemClock),
+            sweep_interval: Some(Duration::from_secs(1)),
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::hash_map::Entry;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+use std::vec;
+
+use crate::engines::KvPair;
+use crate::error;
+
+use super::{EntryPos, Generation, KeyDir, LogReader, Readers};
+
+/// An iterator over a range of a `KvStore`.
+///
+/// The scan works from a snapshot of the keydir taken when it was created,
+/// and holds a handle to every log it reads from. A compaction which
+/// runs while the scan is in progress can therefore neither move nor delete
+/// the entries it will return.
+#[derive(Debug)]
+pub struct KvStoreScan {
+    entries: vec::IntoIter<(Vec<u8>, EntryPos)>,
+    readers: Readers,
+}
+
+impl KvStoreScan {
+    /// Snapshots the keys of `keydir` within `range`, leaving out those which
+    /// expired by `now`, and gets the reader of each generation they are in
+    /// from `reader`.
+    ///
+    /// The keydir must be locked for as long as this runs, so that a
+    /// compaction cannot delete a generation before its log is opened.
+    pub(super) fn new(
+        keydir: &KeyDir,
+        range: impl RangeBounds<Vec<u8>>,
+        now: u64,
+        mut reader: impl FnMut(Generation) -> error::Result<Arc<LogReader>>,
+    ) -> error::Result<Self> {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        let entries: Vec<(Vec<u8>, EntryPos)> = if is_empty(&bounds) {
+            vec![]
+        } else {
+            keydir
+                .range(bounds)
+                .filter(|(_, entry_pos)| !entry_pos.is_expired(now))
+                .map(|(key, &entry_pos)| (key.clone(), entry_pos))
+                .collect()
+        };
+
+        let mut readers = Readers::new();
+        for (_, entry_pos) in &entries {
+            if let Entry::Vacant(vacant) = readers.entry(entry_pos.gen) {
+                vacant.insert(reader(entry_pos.gen)?);
+            }
+        }
+
+        Ok(Self {
+            entries: entries.into_iter(),
+            readers,
+        })
+    }
+
+    fn read(&mut self, (key, entry_pos): (Vec<u8>, EntryPos)) -> error::Result<KvPair> {
+        let reader = self
+            .readers
+            .get(&entry_pos.gen)
+            .expect("Scan reader not opened");
+        let value = reader.read(entry_pos)?.value.unwrap_or_default();
+        Ok((key, value))
+    }
+}
+
+impl Iterator for KvStoreScan {
+    type Item = error::Result<KvPair>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.entries.next()?;
+        Some(self.read(next))
+    }
+}
+
+impl DoubleEndedIterator for KvStoreScan {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let next = self.entries.next_back()?;
+        Some(self.read(next))
+    }
+}
+
+/// Returns `true` if no key can fall within `bounds`.
+///
+/// `BTreeMap::range` panics on such bounds rather than returning nothing.
+fn is_empty(bounds: &(Bound<Vec<u8>>, Bound<Vec<u8>>)) -> bool {
+    match bounds {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
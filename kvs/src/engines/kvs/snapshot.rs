@@ -0,0 +1,174 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::clock::{self, Clock};
+use crate::engines::prefix_end;
+use crate::error;
+use crate::EncryptionKey;
+
+use super::{stale_path, Generation, KeyDir, KvStoreScan, LogReader, Readers};
+
+/// A read-only view of a `KvStore` as of a single moment, created with
+/// `KvStore::snapshot`.
+///
+/// Writes to the store after the snapshot was taken are not visible through
+/// it. The snapshot copies the keydir and pins every generation it refers
+/// to, so compaction leaves those logs in place until the snapshot is
+/// dropped.
+///
+/// Keys still expire while the snapshot is held, according to the store's
+/// clock.
+#[derive(Debug)]
+pub struct Snapshot {
+    log_dir: PathBuf,
+    keydir: KeyDir,
+    readers: Readers,
+    seq: u64,
+    pins: Arc<Mutex<Pins>>,
+    clock: Arc<dyn Clock>,
+    key: Option<EncryptionKey>,
+}
+
+impl Snapshot {
+    /// Pins the generations `keydir` refers to and wraps it in a snapshot.
+    ///
+    /// The store's keydir must be locked for as long as this runs, so that a
+    /// compaction cannot delete a generation before it is pinned.
+    pub(super) fn new(
+        log_dir: &Path,
+        keydir: KeyDir,
+        seq: u64,
+        pins: Arc<Mutex<Pins>>,
+        clock: Arc<dyn Clock>,
+        key: Option<EncryptionKey>,
+    ) -> Self {
+        pins.lock().unwrap().pin(gens(&keydir));
+
+        Self {
+            log_dir: log_dir.to_owned(),
+            keydir,
+            readers: Readers::new(),
+            seq,
+            pins,
+            clock,
+            key,
+        }
+    }
+
+    /// Returns the sequence number of the latest write the snapshot sees.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Gets the value of a key as of the snapshot.
+    pub fn get(&mut self, key: impl Into<Vec<u8>>) -> error::Result<Option<Vec<u8>>> {
+        let now = clock::to_millis(self.clock.now());
+        let entry_pos = match self.keydir.get(&key.into()) {
+            Some(&entry_pos) if !entry_pos.is_expired(now) => entry_pos,
+            _ => return Ok(None),
+        };
+
+        let reader = match self.readers.get(&entry_pos.gen) {
+            Some(reader) => reader,
+            None => {
+                let reader = LogReader::open(&self.log_dir, entry_pos.gen, self.key.as_ref())?;
+                let reader = Arc::new(reader);
+                self.readers.entry(entry_pos.gen).or_insert(reader)
+            }
+        };
+        Ok(Some(reader.read(entry_pos)?.value.unwrap_or_default()))
+    }
+
+    /// Scans the key-value pairs whose keys fall within a range, as of the
+    /// snapshot.
+    pub fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> error::Result<KvStoreScan> {
+        let now = clock::to_millis(self.clock.now());
+        KvStoreScan::new(&self.keydir, range, now, |gen| {
+            match self.readers.get(&gen) {
+                Some(reader) => Ok(Arc::clone(reader)),
+                None => Ok(Arc::new(LogReader::open(
+                    &self.log_dir,
+                    gen,
+                    self.key.as_ref(),
+                )?)),
+            }
+        })
+    }
+
+    /// Scans the key-value pairs whose keys start with a prefix, as of the
+    /// snapshot.
+    pub fn scan_prefix(&self, prefix: impl Into<Vec<u8>>) -> error::Result<KvStoreScan> {
+        let prefix = prefix.into();
+        let end = prefix_end(&prefix);
+        self.scan((Bound::Included(prefix), end))
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut pins = self.pins.lock().unwrap();
+        pins.release(&self.log_dir, gens(&self.keydir));
+    }
+}
+
+/// The generations pinned by live snapshots and checkpoints.
+#[derive(Debug, Default)]
+pub struct Pins {
+    counts: HashMap<Generation, usize>,
+    // Pinned generations which a compaction has made stale.
+    stale: HashSet<Generation>,
+}
+
+impl Pins {
+    /// Pins generations, so that compaction keeps them until they are
+    /// released.
+    pub fn pin(&mut self, gens: HashSet<Generation>) {
+        for gen in gens {
+            *self.counts.entry(gen).or_insert(0) += 1;
+        }
+    }
+
+    /// Unpins generations, returning the stale ones which are no longer
+    /// pinned by any snapshot.
+    fn unpin(&mut self, gens: HashSet<Generation>) -> Vec<Generation> {
+        let mut unpinned = vec![];
+        for gen in gens {
+            let count = self.counts.get_mut(&gen).expect("Generation not pinned");
+            *count -= 1;
+            if *count == 0 {
+                self.counts.remove(&gen);
+                if self.stale.remove(&gen) {
+                    unpinned.push(gen);
+                }
+            }
+        }
+        unpinned
+    }
+
+    /// Unpins generations, removing the stale ones which are no longer pinned
+    /// by anything.
+    pub fn release(&mut self, log_dir: &Path, gens: HashSet<Generation>) {
+        for gen in self.unpin(gens) {
+            if let Err(e) = fs::remove_file(stale_path(log_dir, gen)) {
+                warn!("Failed to remove stale generation {}: {}", gen, e);
+            }
+        }
+    }
+
+    /// Marks a generation as stale, returning `true` if it is pinned and so
+    /// must be kept until it is released.
+    pub fn retire(&mut self, gen: Generation) -> bool {
+        let pinned = self.counts.contains_key(&gen);
+        if pinned {
+            self.stale.insert(gen);
+        }
+        pinned
+    }
+}
+
+fn gens(keydir: &KeyDir) -> HashSet<Generation> {
+    keydir.values().map(|entry_pos| entry_pos.gen).collect()
+}
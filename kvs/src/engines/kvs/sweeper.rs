@@ -0,0 +1,70 @@
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::clock::{self, Clock};
+
+use super::{EntryPos, Expired, KeyDir};
+
+/// A thread which periodically removes expired keys from the keydir.
+pub struct Sweeper {
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Sweeper {
+    /// Spawns a thread which sweeps the keydir every `interval`.
+    pub fn spawn(
+        keydir: Arc<RwLock<KeyDir>>,
+        expired: Arc<Expired>,
+        clock: Arc<dyn Clock>,
+        interval: Duration,
+    ) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::Builder::new()
+            .name(String::from("kvs-sweeper"))
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    sweep(&keydir, &expired, clock::to_millis(clock.now()));
+                }
+            })
+            .expect("Cannot spawn sweeper thread");
+
+        Self { stop, handle }
+    }
+
+    /// Stops the sweeper thread and waits for it to exit.
+    pub fn stop(self) {
+        drop(self.stop);
+        self.handle.join().expect("Sweeper thread panicked");
+    }
+}
+
+/// Removes every key which expired by `now` from the keydir, handing them to
+/// the store through `expired`.
+///
+/// Expired keys are found under a read lock, so that reads carry on while the
+/// keydir is searched, and only removed if they were not written to since.
+fn sweep(keydir: &RwLock<KeyDir>, expired: &Expired, now: u64) {
+    let swept: Vec<(Vec<u8>, EntryPos)> = keydir
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(_, entry_pos)| entry_pos.is_expired(now))
+        .map(|(key, &entry_pos)| (key.clone(), entry_pos))
+        .collect();
+    if swept.is_empty() {
+        return;
+    }
+
+    debug!("Sweeping {} expired keys", swept.len());
+    let mut keydir = keydir.write().unwrap();
+    let mut expired = expired.lock().unwrap();
+    for (key, entry_pos) in swept {
+        if keydir.get(&key) == Some(&entry_pos) {
+            keydir.remove(&key);
+            expired.push((key, entry_pos));
+        }
+    }
+}
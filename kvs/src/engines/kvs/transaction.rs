@@ -0,0 +1,101 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::entry::Entry;
+use crate::error;
+use crate::KvsError;
+
+use super::KvStore;
+
+/// An optimistic transaction on a `KvStore`, started with `KvStore::begin`.
+///
+/// Reads go through to the store and remember which version of each key they
+/// saw, while writes are buffered in the transaction. Committing checks that
+/// none of the keys read have been written since, then applies the buffered
+/// writes atomically. If a key has changed, the commit fails with
+/// `KvsError::Conflict` and nothing is written; the transaction can then be
+/// retried from the start.
+///
+/// A transaction holds its own handle to the store and takes no locks until
+/// it commits, so other writes may happen while it is open.
+pub struct Transaction {
+    store: KvStore,
+    // The sequence number each key had when first read, or `None` if it did
+    // not exist.
+    reads: HashMap<Vec<u8>, Option<u64>>,
+    // The buffered writes, with `None` for a removal.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Transaction {
+    pub(super) fn new(store: KvStore) -> Self {
+        Self {
+            store,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Gets the value of a key.
+    ///
+    /// Keys written earlier in the transaction read back the buffered value.
+    pub fn get(&mut self, key: impl Into<Vec<u8>>) -> error::Result<Option<Vec<u8>>> {
+        let key = key.into();
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+
+        let read = self.store.read(&key)?;
+        self.reads
+            .entry(key)
+            .or_insert_with(|| read.as_ref().map(|&(seq, _)| seq));
+        Ok(read.map(|(_, value)| value))
+    }
+
+    /// Buffers setting a key to a value.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.writes.insert(key.into(), Some(value.into()));
+    }
+
+    /// Buffers removing a key.
+    ///
+    /// Removing a key which does not exist is not an error.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) {
+        self.writes.insert(key.into(), None);
+    }
+
+    /// Validates the transaction's reads and applies its writes.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::Conflict` if any key the transaction read has been
+    /// written to since.
+    pub fn commit(self) -> error::Result<()> {
+        let store = self.store.clone();
+        // The commit runs with the writer locked, which keeps other writes out
+        // between validating the reads and applying the writes.
+        store.commit(move |writer| {
+            let now = self.store.now();
+            {
+                let keydir = self.store.inner.keydir.read().unwrap();
+                for (key, seq) in &self.reads {
+                    let entry_pos = keydir
+                        .get(key)
+                        .filter(|entry_pos| !entry_pos.is_expired(now));
+                    if entry_pos.map(|entry_pos| entry_pos.seq) != *seq {
+                        return Err(KvsError::Conflict);
+                    }
+                }
+            }
+
+            let entries = self
+                .writes
+                .into_iter()
+                .map(|(key, value)| match value {
+                    Some(value) => Entry::set(key, value),
+                    None => Entry::remove(key),
+                })
+                .collect();
+            writer.write_batch(entries)
+        })
+    }
+}
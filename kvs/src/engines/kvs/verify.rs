@@ -0,0 +1,193 @@
+use std::io::{self, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::clock;
+use crate::entry::{self, Record};
+use crate::error;
+use crate::{EncryptionKey, KvsError};
+
+use super::hint::{Hint, Hints};
+use super::{apply_hints, sorted_gen_list, BufReaderWithPos, EntryPos, Generation, KeyDir};
+use super::{KvStoreOptions, LogReader};
+
+/// What verifying a data directory found.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// The number of generations whose logs were checked.
+    pub generations: usize,
+    /// The number of entries which passed their checks, counting each entry
+    /// of a batch separately.
+    pub entries: u64,
+    /// The number of those entries which hold the current value of a key.
+    pub live: u64,
+    /// The ranges of the logs which hold corrupt entries, in the order they
+    /// were found.
+    pub corrupt: Vec<CorruptRange>,
+    /// The incomplete entry at the end of the newest generation, if there is
+    /// one.
+    ///
+    /// This is left behind by a crash, or by a store still writing the entry,
+    /// and is not corruption: opening the store drops it.
+    pub torn: Option<CorruptRange>,
+}
+
+impl VerifyReport {
+    /// Returns the number of entries which were overwritten, removed or have
+    /// expired, and would be dropped by a compaction.
+    pub fn dead(&self) -> u64 {
+        self.entries - self.live
+    }
+
+    /// Returns `true` if no corrupt entries were found.
+    pub fn is_ok(&self) -> bool {
+        self.corrupt.is_empty()
+    }
+}
+
+/// A range of bytes in a generation's log which does not hold valid
+/// entries.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorruptRange {
+    /// The generation of the log.
+    pub gen: Generation,
+    /// The offsets of the bytes within the log.
+    pub range: Range<u64>,
+    /// What was wrong with the first entry in the range.
+    pub error: String,
+}
+
+/// Checks every entry in the logs of the data directory `log_dir` against
+/// its checksum and length, without opening the store.
+///
+/// Unlike `KvStore::open`, this neither stops at the first corrupt entry
+/// nor modifies the directory. A corrupt entry is skipped by the length it
+/// records, so a damaged length may make the following entries look corrupt
+/// too; adjacent corrupt entries are reported as a single range. Hint files
+/// are not read, so every log is replayed in full.
+///
+/// `key` must be the key the store is encrypted with, or `None` if it is not
+/// encrypted. The store should not be running: a compaction could remove
+/// generations before they are checked.
+///
+/// # Errors
+///
+/// Fails if a log's header cannot be read, if it is encrypted with a key
+/// other than `key`, or if any file cannot be read.
+///
+/// # Examples
+///
+/// ```
+/// use kvs::{KvStore, KvsEngine};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().unwrap();
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+/// store.set("foo", "bar").unwrap();
+/// store.set("foo", "baz").unwrap();
+/// drop(store);
+///
+/// let report = kvs::verify(&temp_dir.path().join(".kvsdata"), None).unwrap();
+/// assert!(report.is_ok());
+/// assert_eq!((report.live, report.dead()), (1, 1));
+/// ```
+pub fn verify(log_dir: &Path, key: Option<&EncryptionKey>) -> error::Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    let mut keydir = KeyDir::new();
+    let now = clock::to_millis(SystemTime::now());
+
+    let gen_list = sorted_gen_list(log_dir)?;
+    for &gen in &gen_list {
+        let log_reader = LogReader::open(log_dir, gen, key)?;
+        let newest = Some(&gen) == gen_list.last();
+        let data_len = log_reader.file.metadata()?.len() - log_reader.data_start;
+        let hints = verify_log(gen, &log_reader, newest, &mut report)?;
+        apply_hints(hints, data_len, &mut keydir, now);
+        report.generations += 1;
+    }
+
+    report.live = keydir.len() as u64;
+    Ok(report)
+}
+
+/// Replays a log file, recording every corrupt range in `report` and
+/// returning the final state of every key it touches.
+///
+/// If `newest` is set, an entry at the end of the log which is incomplete or
+/// fails its checksum is recorded as a torn write, as `KvStore::open` treats
+/// it. Any other entry which is cut short, fails its checksum or cannot be
+/// decoded is corrupt.
+fn verify_log(
+    gen: Generation,
+    log_reader: &LogReader,
+    newest: bool,
+    report: &mut VerifyReport,
+) -> error::Result<Hints> {
+    let version = log_reader.version;
+    let key = log_reader.key.as_ref();
+    let log_len = log_reader.file.metadata()?.len();
+    let read_buffer_size = KvStoreOptions::default().read_buffer_size;
+    let reader = &mut BufReaderWithPos::with_capacity(read_buffer_size, &log_reader.file)?;
+    let mut pos = reader.seek(SeekFrom::Start(log_reader.data_start))?;
+    let mut hints = Hints::new();
+
+    while pos < log_len {
+        let record = match entry::record_from_reader(reader, version, gen, pos, key) {
+            Ok(record) => record,
+            Err(e) => {
+                let (end, torn) = match e {
+                    KvsError::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                        (log_len, newest)
+                    }
+                    KvsError::Corruption { .. } => (reader.pos, newest && reader.pos == log_len),
+                    KvsError::Io(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                        (reader.pos, false)
+                    }
+                    KvsError::Authentication { .. }
+                    | KvsError::UnsupportedCodec { .. }
+                    | KvsError::Unexpectedcommandtype => (reader.pos, false),
+                    e => return Err(e),
+                };
+                let corrupt = CorruptRange {
+                    gen,
+                    range: pos..end,
+                    error: e.to_string(),
+                };
+
+                if torn {
+                    report.torn = Some(corrupt);
+                    break;
+                }
+                match report.corrupt.last_mut() {
+                    Some(last) if last.gen == gen && last.range.end == pos => {
+                        last.range.end = end;
+                    }
+                    _ => report.corrupt.push(corrupt),
+                }
+                pos = end;
+                continue;
+            }
+        };
+        let entries = match record {
+            Record::Entry(entry) => vec![(0..reader.pos - pos, entry)],
+            Record::Batch(entries) => entries,
+        };
+
+        for (range, entry) in entries {
+            let entry_pos = EntryPos {
+                expires_at: entry.expires_at,
+                ..(gen, pos + range.start..pos + range.end).into()
+            };
+            match entry.value {
+                Some(_) => hints.insert(entry.key, Hint::Put(entry_pos)),
+                None => hints.insert(entry.key, Hint::Delete(entry_pos)),
+            };
+            report.entries += 1;
+        }
+
+        pos = reader.pos;
+    }
+
+    Ok(hints)
+}
@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::engines::sync::SyncState;
+use crate::entry::{self, Entry};
+use crate::error;
+use crate::KvsError;
+
+use super::compaction::Compaction;
+use super::hint::{self, Hint, Hints};
+use super::snapshot::Pins;
+use super::{new_log_file, BufWriterWithPos, CompactionTrigger, KvStoreOptions, LogReader};
+use super::{EntryPos, Expired, Generation, KeyDir, Readers, MIN_COMPACTION_BYTES};
+
+/// The write side of a `KvStore`.
+///
+/// There is one writer per store, behind a mutex, so that every write is
+/// appended to the log and applied to the keydir in a single order.
+pub struct Writer {
+    pub log_dir: PathBuf,
+    pub options: KvStoreOptions,
+    pub readers: Arc<RwLock<Readers>>,
+    pub keydir: Arc<RwLock<KeyDir>>,
+    pub pins: Arc<Mutex<Pins>>,
+    pub expired: Arc<Expired>,
+    // `None` if the store was opened read-only.
+    pub writer: Option<BufWriterWithPos<File>>,
+    // Writes to the current generation which have not been synced yet.
+    pub sync: SyncState,
+    // Whether the sync policy called for a sync before the current group of
+    // writes is acknowledged.
+    pub needs_sync: bool,
+    // Tombstones written to the current generation, kept for its hint file.
+    pub removed: HashMap<Vec<u8>, EntryPos>,
+    pub current_gen: Generation,
+    // The sequence number of the latest write.
+    pub seq: u64,
+    pub live: u64,
+    pub uncompacted: u64,
+    pub compaction: Option<Compaction>,
+}
+
+impl Writer {
+    /// Appends an entry to the log and applies it to the keydir.
+    pub fn write(&mut self, entry: Entry) -> error::Result<()> {
+        let entry_pos = self.append(&entry)?;
+        self.apply(entry, entry_pos);
+
+        self.maintain()
+    }
+
+    /// Appends entries to the log as a single batch record and applies them
+    /// to the keydir.
+    pub fn write_batch(&mut self, entries: Vec<Entry>) -> error::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let (bytes, ranges) = entry::batch_as_durable_bytes(
+            &entries,
+            self.options.compression,
+            self.options.compression_threshold,
+            self.options.encryption_key.as_ref(),
+            self.current_gen,
+            self.pos()?,
+        )?;
+        let start = self.append_bytes(&bytes)?.start;
+        // The batch's own prefix never holds a live value.
+        self.uncompacted += entry::PREFIX_SIZE as u64;
+
+        for (entry, range) in entries.into_iter().zip(ranges) {
+            let entry_pos = (self.current_gen, start + range.start..start + range.end).into();
+            self.apply(entry, entry_pos);
+        }
+
+        self.maintain()
+    }
+
+    /// Accounts for keys which expired and were removed from the keydir by
+    /// the sweeper or a read.
+    ///
+    /// An expired key from the current generation gets a delete hint, since
+    /// its generation's hint file no longer has a put to mark it expired.
+    fn reap_expired(&mut self) {
+        self.reap_resized();
+        for (key, entry_pos) in self.expired.lock().unwrap().drain(..) {
+            self.live -= entry_pos.len;
+            self.uncompacted += entry_pos.len;
+            if entry_pos.gen == self.current_gen {
+                self.removed.insert(key, entry_pos);
+            }
+        }
+    }
+
+    /// Accounts for live entries which the compaction rewrote at a different
+    /// length, such as when it upgrades or encrypts old logs.
+    ///
+    /// This must be called with the keydir locked before comparing any entry
+    /// in it with the live bytes.
+    fn reap_resized(&mut self) {
+        if let Some(compaction) = &self.compaction {
+            let (old_len, new_len) = compaction.take_resized();
+            self.live = self.live - old_len + new_len;
+        }
+    }
+
+    /// Appends an entry to the current generation, returning its position.
+    fn append(&mut self, entry: &Entry) -> error::Result<EntryPos> {
+        let range = self.append_bytes(&entry.encode(
+            self.options.compression,
+            self.options.compression_threshold,
+            self.options.encryption_key.as_ref(),
+            self.current_gen,
+            self.pos()?,
+        )?)?;
+        Ok((self.current_gen, range).into())
+    }
+
+    /// Returns the offset the next record is appended at.
+    fn pos(&self) -> error::Result<u64> {
+        Ok(self.writer.as_ref().ok_or(KvsError::ReadOnly)?.pos)
+    }
+
+    /// Appends an encoded record to the current generation, returning the
+    /// range it was written to.
+    fn append_bytes(&mut self, bytes: &[u8]) -> error::Result<Range<u64>> {
+        let writer = self.writer.as_mut().ok_or(KvsError::ReadOnly)?;
+
+        let pos = writer.pos;
+        writer.write_all(bytes)?;
+        // Syncing is left to the end of the group, but the entry is flushed
+        // now so that it can be read back as soon as it is in the keydir.
+        writer.flush()?;
+        self.needs_sync |= self.sync.wrote(bytes.len() as u64);
+
+        Ok(pos..writer.pos)
+    }
+
+    /// Syncs the log once for a group of writes, if any of them called for
+    /// it.
+    pub fn sync_group(&mut self) -> error::Result<()> {
+        if !self.needs_sync {
+            return Ok(());
+        }
+        self.needs_sync = false;
+        if let Some(writer) = self.writer.as_mut() {
+            writer.sync()?;
+            self.sync.synced();
+        }
+        Ok(())
+    }
+
+    /// Syncs the current generation if it has writes which the sync policy
+    /// still owes a sync.
+    pub fn sync_pending(&mut self) -> error::Result<()> {
+        if let Some(writer) = self.writer.as_mut() {
+            if self.sync.is_dirty() {
+                writer.sync()?;
+                self.sync.synced();
+            }
+        }
+        Ok(())
+    }
+
+    /// Points the keydir at an entry which was just appended, and accounts
+    /// for the bytes it makes stale.
+    fn apply(&mut self, entry: Entry, mut entry_pos: EntryPos) {
+        self.seq += 1;
+        entry_pos.seq = self.seq;
+        entry_pos.expires_at = entry.expires_at;
+
+        let keydir = Arc::clone(&self.keydir);
+        let mut keydir = keydir.write().unwrap();
+        self.reap_resized();
+        let old_entry = match entry.value {
+            Some(_) => {
+                self.removed.remove(&entry.key);
+                keydir.insert(entry.key, entry_pos)
+            }
+            None => {
+                let old_entry = keydir.remove(&entry.key);
+                self.removed.insert(entry.key, entry_pos);
+                old_entry
+            }
+        };
+        // The rest only touches the writer's own accounting.
+        drop(keydir);
+
+        match entry.value {
+            Some(_) => self.live += entry_pos.len,
+            None => self.uncompacted += entry_pos.len,
+        }
+
+        if let Some(old_entry) = old_entry {
+            self.live -= old_entry.len;
+            self.uncompacted += old_entry.len;
+        }
+    }
+
+    /// Rotates the log and starts a compaction once the options call for it.
+    fn maintain(&mut self) -> error::Result<()> {
+        self.reap_expired();
+        if let Some(writer) = &self.writer {
+            if writer.pos >= self.options.max_file_size {
+                self.rotate()?;
+            }
+        }
+
+        self.finish_compaction(false);
+        let should_compact = match self.options.compaction_trigger {
+            CompactionTrigger::Bytes(bytes) => self.uncompacted > bytes,
+            CompactionTrigger::Ratio(ratio) => {
+                self.uncompacted >= MIN_COMPACTION_BYTES
+                    && self.uncompacted as f64 > self.live as f64 * ratio
+            }
+        };
+        if should_compact && self.compaction.is_none() {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    /// Seals the current generation and moves the writer on to the next one.
+    fn rotate(&mut self) -> error::Result<()> {
+        self.sync_pending()?;
+        self.write_hints()?;
+
+        let sealed_gen = self.current_gen;
+        self.current_gen += 1;
+        self.writer = Some(self.new_log_file(self.current_gen)?);
+        self.removed.clear();
+
+        if self.options.mmap_reads {
+            let reader = LogReader::open_sealed(&self.log_dir, sealed_gen, &self.options)?;
+            self.readers
+                .write()
+                .unwrap()
+                .insert(sealed_gen, Arc::new(reader));
+        }
+
+        Ok(())
+    }
+
+    /// Seals the current generation, returning it, and moves the writer on to
+    /// the next one.
+    pub fn seal(&mut self) -> error::Result<Generation> {
+        if self.writer.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+        let sealed_gen = self.current_gen;
+        self.rotate()?;
+        Ok(sealed_gen)
+    }
+
+    /// Starts compacting the write-ahead log in the background.
+    ///
+    /// The writer moves on to a fresh generation and everything before it is
+    /// compacted into the generations in between, leaving room for one more
+    /// than the live bytes fill at the maximum file size.
+    pub fn compact(&mut self) -> error::Result<()> {
+        self.sync_pending()?;
+        let reserved = self.live / self.options.max_file_size.max(1) + 1;
+        let compaction_gens = self.current_gen + 1..self.current_gen + 1 + reserved;
+        self.current_gen = compaction_gens.end;
+
+        self.writer = Some(self.new_log_file(self.current_gen)?);
+        self.removed.clear();
+
+        self.compaction = Some(Compaction::spawn(
+            self.log_dir.clone(),
+            self.options.clone(),
+            Arc::clone(&self.keydir),
+            Arc::clone(&self.pins),
+            Arc::clone(&self.expired),
+            compaction_gens,
+            self.uncompacted,
+        ));
+
+        Ok(())
+    }
+
+    /// Reaps the background compaction, if it has finished or `wait` is set.
+    ///
+    /// Readers of the generations it deleted are dropped here; the ones for
+    /// the compacted generation are opened on first use. The stale bytes it
+    /// reclaimed are only accounted for once it succeeds, so that a failed
+    /// compaction is retried.
+    pub fn finish_compaction(&mut self, wait: bool) {
+        match self.compaction.take() {
+            Some(compaction) if wait || compaction.is_finished() => {
+                let compaction_gens = compaction.gens.clone();
+                let reclaimed = compaction.uncompacted;
+                let (old_len, new_len) = match compaction.join() {
+                    Ok(resized) => resized,
+                    Err(e) => {
+                        error!(
+                            "Failed to compact into generations {:?}: {}",
+                            compaction_gens, e
+                        );
+                        return;
+                    }
+                };
+                self.live = self.live - old_len + new_len;
+                self.readers
+                    .write()
+                    .unwrap()
+                    .retain(|&gen, _| gen >= compaction_gens.start);
+                self.uncompacted = self.uncompacted.saturating_sub(reclaimed);
+            }
+            compaction => self.compaction = compaction,
+        }
+    }
+
+    fn new_log_file(&mut self, gen: Generation) -> error::Result<BufWriterWithPos<File>> {
+        new_log_file(
+            &self.log_dir,
+            gen,
+            &mut self.readers.write().unwrap(),
+            &self.options,
+        )
+    }
+
+    /// Writes the hint file for the current generation.
+    fn write_hints(&mut self) -> error::Result<()> {
+        let writer = match self.writer.as_mut() {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        writer.flush()?;
+
+        let current_gen = self.current_gen;
+        let log_len = writer.pos;
+        let keydir = Arc::clone(&self.keydir);
+        let keydir = keydir.read().unwrap();
+        // Expired keys are reaped while the keydir is locked, so that none
+        // can be missing from both.
+        self.reap_expired();
+        let mut hints: Hints = self
+            .removed
+            .iter()
+            .map(|(key, &entry_pos)| (key.clone(), Hint::Delete(entry_pos)))
+            .collect();
+        hints.extend(
+            keydir
+                .iter()
+                .filter(|(_, entry_pos)| entry_pos.gen == current_gen)
+                .map(|(key, &entry_pos)| (key.clone(), Hint::Put(entry_pos))),
+        );
+
+        hint::write(
+            &self.log_dir,
+            current_gen,
+            log_len,
+            &hints,
+            self.options.encryption_key.as_ref(),
+        )
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.finish_compaction(true);
+        if let Err(e) = self.sync_pending() {
+            warn!("Failed to sync generation {}: {}", self.current_gen, e);
+        }
+        if let Err(e) = self.write_hints() {
+            warn!(
+                "Failed to write hint file for generation {}: {}",
+                self.current_gen, e
+            );
+        }
+    }
+}
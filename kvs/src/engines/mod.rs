@@ -1,27 +1,210 @@
+use std::ops::{Bound, RangeBounds};
+use std::time::{Duration, SystemTime};
+
 use crate::error;
+use crate::WriteBatch;
+
+/// A key-value pair as returned by a scan.
+pub type KvPair = (Vec<u8>, Vec<u8>);
 
 /// Trait for a key value storage engine.
-pub trait KvsEngine {
+///
+/// Keys and values are arbitrary bytes. The string methods are a convenience
+/// layer over the byte methods, which are all an engine has to implement.
+///
+/// An engine is a handle which can be cloned and shared between threads;
+/// every clone operates on the same store.
+pub trait KvsEngine: Clone + Send + Sync + 'static {
+    /// The iterator returned by scans, in ascending key order.
+    ///
+    /// It is double-ended, so `rev` iterates in descending key order.
+    type Scan: DoubleEndedIterator<Item = error::Result<KvPair>>;
+
+    /// Sets the value of a key.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set_bytes(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> error::Result<()>;
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> error::Result<Option<Vec<u8>>>;
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn remove_bytes(&self, key: impl Into<Vec<u8>>) -> error::Result<()>;
+
+    /// Atomically sets a key to `new`, provided its current value is
+    /// `expected`.
+    ///
+    /// `None` stands for a missing key in both: an `expected` of `None` only
+    /// writes if the key does not exist, and a `new` of `None` removes it.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CompareAndSwap` with the current value if it
+    /// does not match `expected`.
+    fn compare_and_swap_bytes(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> error::Result<()>;
+
+    /// Atomically sets the value of a key, provided it already exists.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn set_if_present_bytes(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> error::Result<()>;
+
+    /// Applies every operation in a batch, or none of them.
+    fn write_batch(&self, batch: WriteBatch) -> error::Result<()>;
+
+    /// Scans the key-value pairs whose keys fall within a range.
+    ///
+    /// The scan sees the store as it was when the scan was created; later
+    /// writes are not reflected in it.
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> error::Result<Self::Scan>;
+
+    /// Sets the value of a key, which expires once `ttl` has passed.
+    ///
+    /// An expired key behaves as if it had been removed. Setting the key
+    /// again without a time to live makes it persistent, as does a `ttl` too
+    /// long for its expiry time to be stored.
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> error::Result<()>;
+
+    /// Makes an existing key expire at a point in time.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn expire_at(&self, key: impl Into<Vec<u8>>, at: SystemTime) -> error::Result<()>;
+
+    /// Returns how long a key has left before it expires, or `None` if it
+    /// never does.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn ttl(&self, key: impl Into<Vec<u8>>) -> error::Result<Option<Duration>>;
+
+    /// Scans the key-value pairs whose keys start with a prefix.
+    fn scan_prefix(&self, prefix: impl Into<Vec<u8>>) -> error::Result<Self::Scan> {
+        let prefix = prefix.into();
+        let end = prefix_end(&prefix);
+        self.scan((Bound::Included(prefix), end))
+    }
+
+    /// Atomically sets the value of a key, provided it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CompareAndSwap` with the current value if the
+    /// key exists.
+    fn set_if_absent_bytes(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> error::Result<()> {
+        self.compare_and_swap_bytes(key, None, Some(value.into()))
+    }
+
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&mut self, key: impl Into<String>, value: impl Into<String>) -> error::Result<()>;
+    fn set(&self, key: impl Into<String>, value: impl Into<String>) -> error::Result<()> {
+        self.set_bytes(key.into(), value.into())
+    }
 
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&mut self, key: impl Into<String>) -> error::Result<Option<String>>;
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::FromUtf8` if the value is not valid UTF-8.
+    fn get(&self, key: impl Into<String>) -> error::Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.into())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
 
     /// Removes a given key.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
-    fn remove(&mut self, key: impl Into<String>) -> error::Result<()>;
+    fn remove(&self, key: impl Into<String>) -> error::Result<()> {
+        self.remove_bytes(key.into())
+    }
+
+    /// Atomically sets a string key to `new`, provided its current value is
+    /// `expected`.
+    ///
+    /// See `compare_and_swap_bytes`.
+    fn compare_and_swap(
+        &self,
+        key: impl Into<String>,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> error::Result<()> {
+        self.compare_and_swap_bytes(key.into(), expected.map(Into::into), new.map(Into::into))
+    }
+
+    /// Atomically sets the value of a string key to a string, provided it
+    /// does not exist.
+    ///
+    /// See `set_if_absent_bytes`.
+    fn set_if_absent(&self, key: impl Into<String>, value: impl Into<String>) -> error::Result<()> {
+        self.set_if_absent_bytes(key.into(), value.into())
+    }
+
+    /// Atomically sets the value of a string key to a string, provided it
+    /// already exists.
+    ///
+    /// See `set_if_present_bytes`.
+    fn set_if_present(
+        &self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> error::Result<()> {
+        self.set_if_present_bytes(key.into(), value.into())
+    }
+}
+
+/// Returns the bound which ends a scan of every key starting with `prefix`.
+fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(byte) = end.pop() {
+        if byte < u8::MAX {
+            end.push(byte + 1);
+            return Bound::Excluded(end);
+        }
+    }
+    Bound::Unbounded
 }
 
 mod kvs;
 mod sled;
+mod sync;
 
-pub use self::kvs::KvStore;
-pub use self::sled::SledKvsEngine;
+pub use self::kvs::{
+    backup, restore, verify, BackupStats, CompactionTrigger, CorruptRange, CorruptionPolicy,
+    KvStore, KvStoreOptions, KvStoreScan, Snapshot, Transaction, VerifyReport,
+};
+pub use self::sled::{SledKvsEngine, SledScan};
+pub use self::sync::SyncPolicy;
@@ -1,43 +1,511 @@
-use sled::{Db, Tree};
+use std::convert::TryInto;
+use std::fs;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
 
+use sled::transaction::{ConflictableTransactionResult, TransactionError};
+use sled::{Batch, Db, IVec, Iter, Transactional, Tree};
+
+use crate::clock::{self, Clock, SystemClock};
+use crate::engines::sync::{SyncState, Syncer};
 use crate::error;
-use crate::KvsError;
+use crate::{KvsError, SyncPolicy, WriteBatch};
+
+use super::{KvPair, KvsEngine};
+
+// Every value stored in sled starts with a tag saying whether it expires.
+const PERSISTENT: u8 = 0;
+// An expiring value's tag is followed by the expiry time, in milliseconds
+// since the Unix epoch as a little-endian `u64`.
+const EXPIRING: u8 = 1;
 
-use super::KvsEngine;
+// The tree holding the engine's own metadata, apart from the stored keys.
+const META_TREE: &[u8] = b"kvs_meta";
+// Present in the metadata once every stored value is tagged. Databases
+// written before keys could expire hold untagged values and lack it.
+const TAGGED_KEY: &[u8] = b"tagged";
+// The last key tagged so far, while the values are being tagged.
+const TAG_CURSOR_KEY: &[u8] = b"tag_cursor";
+// How many values are tagged in each transaction.
+const TAG_CHUNK_SIZE: usize = 1000;
+
+// A stored value as read from sled, along with its decoded value and expiry.
+type LiveValue = (IVec, Vec<u8>, Option<u64>);
 
 /// Wrapper of `sled::Db`
+///
+/// Writes are flushed to disk according to a `SyncPolicy`, which defaults
+/// to `SyncPolicy::Always`.
+///
+/// Keys set with a time to live stop being visible once they expire. They
+/// are removed from the database by a background sweep, or when they are
+/// next read.
+///
+/// This engine uses sled 0.34. Databases written by sled 0.22, which older
+/// versions of kvs used, cannot be opened by it: copy them into a new
+/// directory with `kvs-admin migrate-sled <old_dir> <new_dir>` first.
 #[derive(Clone)]
-pub struct SledKvsEngine(Db);
+pub struct SledKvsEngine {
+    db: Db,
+    clock: Arc<dyn Clock>,
+    sync: Arc<Mutex<SyncState>>,
+    // Flushes pending writes in the background for `SyncPolicy::EveryMillis`.
+    syncer: Option<Arc<Syncer>>,
+    // Removes expired keys in the background.
+    sweeper: Option<Arc<Syncer>>,
+    // Held shared by every write, and exclusively by a checkpoint so that it
+    // copies a single moment.
+    writes: Arc<RwLock<()>>,
+}
 
 impl SledKvsEngine {
     /// Creates a `SledKvsEngine` from `sled::Db`.
-    pub fn new(db: Db) -> Self {
-        Self(db)
+    ///
+    /// Values written before keys could expire are tagged as never expiring
+    /// the first time the database is opened. They are tagged a chunk at a
+    /// time, each in its own transaction, and tagging resumes where it left
+    /// off if it is interrupted.
+    ///
+    /// # Errors
+    ///
+    /// Fails if sled cannot read the database or tag its values.
+    pub fn new(db: Db) -> error::Result<Self> {
+        Self::with_clock(db, Arc::new(SystemClock))
     }
-}
 
-impl KvsEngine for SledKvsEngine {
-    fn set(&mut self, key: impl Into<String>, value: impl Into<String>) -> error::Result<()> {
-        let tree: &Tree = &self.0;
-        tree.set(key.into(), value.into().into_bytes())
-            .map(|_| ())?;
-        tree.flush()?;
+    /// Creates a `SledKvsEngine` from `sled::Db` which expires keys
+    /// according to `clock`.
+    pub fn with_clock(db: Db, clock: Arc<dyn Clock>) -> error::Result<Self> {
+        tag_values(&db)?;
+        let engine = Self {
+            db,
+            clock,
+            sync: Arc::new(Mutex::new(SyncState::new(SyncPolicy::Always))),
+            syncer: None,
+            sweeper: None,
+            writes: Arc::new(RwLock::new(())),
+        };
+        Ok(engine.sweep_interval(Some(Duration::from_secs(1))))
+    }
+
+    /// Sets when writes are flushed to disk.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{KvsEngine, SledKvsEngine, SyncPolicy};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let db = sled::open(temp_dir.path()).unwrap();
+    ///
+    /// let engine = SledKvsEngine::new(db).unwrap().sync_policy(SyncPolicy::EveryMillis(100));
+    /// engine.set("foo", "bar").unwrap();
+    /// ```
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync = Arc::new(Mutex::new(SyncState::new(sync_policy)));
+        self.syncer = sync_policy.interval().map(|interval| {
+            let db = self.db.clone();
+            let sync = Arc::clone(&self.sync);
+            Arc::new(Syncer::spawn("kvs-syncer", interval, move || {
+                let mut sync = sync.lock().unwrap();
+                if sync.is_dirty() {
+                    match db.flush() {
+                        Ok(_) => sync.synced(),
+                        Err(e) => error!("Failed to flush sled: {}", e),
+                    }
+                }
+            }))
+        });
+        self
+    }
+
+    /// Sets how often expired keys are swept from the database in the
+    /// background, or `None` to only remove them when they are next read.
+    ///
+    /// Defaults to every second.
+    pub fn sweep_interval(mut self, sweep_interval: Option<Duration>) -> Self {
+        self.sweeper = sweep_interval.map(|interval| {
+            let db = self.db.clone();
+            let clock = Arc::clone(&self.clock);
+            let writes = Arc::clone(&self.writes);
+            Arc::new(Syncer::spawn("kvs-sweeper", interval, move || {
+                if let Err(e) = sweep(&db, clock::to_millis(clock.now()), &writes) {
+                    error!("Failed to sweep expired keys: {}", e);
+                }
+            }))
+        });
+        self
+    }
+
+    /// Writes a consistent copy of the database into `dest_dir`, which can be
+    /// opened with `sled::open`.
+    ///
+    /// Writes wait while the copy is made, but reads carry on. `dest_dir` must
+    /// not hold a database already.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{KvsEngine, SledKvsEngine};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let engine = SledKvsEngine::new(sled::open(temp_dir.path()).unwrap()).unwrap();
+    /// engine.set("foo", "bar").unwrap();
+    ///
+    /// let backup_dir = TempDir::new().unwrap();
+    /// engine.checkpoint(backup_dir.path().join("db")).unwrap();
+    ///
+    /// let backup = SledKvsEngine::new(sled::open(backup_dir.path().join("db")).unwrap()).unwrap();
+    /// assert_eq!(backup.get("foo").unwrap(), Some(String::from("bar")));
+    /// ```
+    pub fn checkpoint(&self, dest_dir: impl AsRef<Path>) -> error::Result<()> {
+        let dest_dir = dest_dir.as_ref();
+        if dest_dir.exists() && fs::read_dir(dest_dir)?.next().is_some() {
+            return Err(KvsError::String(format!(
+                "Checkpoint directory {} is not empty",
+                dest_dir.display()
+            )));
+        }
+
+        let _writes = self.writes.write().unwrap();
+        let dest = sled::open(dest_dir)?;
+        dest.import(self.db.export());
+        dest.flush()?;
         Ok(())
     }
 
-    fn get(&mut self, key: impl Into<String>) -> error::Result<Option<String>> {
-        let tree: &Tree = &self.0;
-        Ok(tree
-            .get(key.into())?
-            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
+    fn now(&self) -> u64 {
+        clock::to_millis(self.clock.now())
     }
 
-    fn remove(&mut self, key: impl Into<String>) -> error::Result<()> {
-        let tree: &Tree = &self.0;
-        tree.del(key.into())?.ok_or(KvsError::KeyNotFound)?;
-        tree.flush()?;
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> error::Result<()> {
+        let _writes = self.writes.read().unwrap();
+        let tree: &Tree = &self.db;
+        let value = encode(&value, expires_at);
+        let len = key.len() + value.len();
+        tree.insert(key, value)?;
+        self.wrote(len)
+    }
+
+    /// Records a write of `len` bytes, flushing it to disk if the sync policy
+    /// calls for it.
+    fn wrote(&self, len: usize) -> error::Result<()> {
+        let mut sync = self.sync.lock().unwrap();
+        if sync.wrote(len as u64) {
+            self.db.flush()?;
+            sync.synced();
+        }
         Ok(())
     }
+
+    /// Gets a key's raw value along with its decoded value and expiry, unless
+    /// it is missing or has expired.
+    ///
+    /// An expired value is removed, unless it is overwritten concurrently.
+    fn get_live(&self, key: &[u8]) -> error::Result<Option<LiveValue>> {
+        let tree: &Tree = &self.db;
+        let raw = match tree.get(key)? {
+            Some(raw) => raw,
+            None => return Ok(None),
+        };
+        let (value, expires_at) = decode(&raw)?;
+        if clock::is_expired(expires_at, self.now()) {
+            // A failed swap means the key was written again in the meantime.
+            let _ = tree.compare_and_swap(key, Some(raw), None as Option<IVec>)?;
+            return Ok(None);
+        }
+        Ok(Some((raw, value, expires_at)))
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    type Scan = SledScan;
+
+    fn set_bytes(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> error::Result<()> {
+        self.insert(key.into(), value.into(), None)
+    }
+
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> error::Result<Option<Vec<u8>>> {
+        Ok(self.get_live(&key.into())?.map(|(_, value, _)| value))
+    }
+
+    fn remove_bytes(&self, key: impl Into<Vec<u8>>) -> error::Result<()> {
+        let key = key.into();
+        let _writes = self.writes.read().unwrap();
+        self.get_live(&key)?.ok_or(KvsError::KeyNotFound)?;
+        let tree: &Tree = &self.db;
+        tree.remove(&key)?.ok_or(KvsError::KeyNotFound)?;
+        self.wrote(key.len())
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> error::Result<()> {
+        let key = key.into();
+        let _writes = self.writes.read().unwrap();
+        let tree: &Tree = &self.db;
+        // Stored values carry their expiry, so compare the decoded value and
+        // then swap out the exact bytes it was decoded from. Retry if those
+        // bytes changed in between.
+        loop {
+            let (raw, current) = match self.get_live(&key)? {
+                Some((raw, value, _)) => (Some(raw), Some(value)),
+                None => (None, None),
+            };
+            if current != expected {
+                return Err(KvsError::CompareAndSwap { current });
+            }
+
+            let new = new.as_ref().map(|value| encode(value, None));
+            let len = key.len() + new.as_ref().map_or(0, Vec::len);
+            if tree.compare_and_swap(&key, raw, new)?.is_ok() {
+                break self.wrote(len);
+            }
+        }
+    }
+
+    fn set_if_present_bytes(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> error::Result<()> {
+        let key = key.into();
+        let new = encode(&value.into(), None);
+        let len = key.len() + new.len();
+        let _writes = self.writes.read().unwrap();
+        let tree: &Tree = &self.db;
+        // Retry if the value is overwritten between reading it and swapping
+        // in the new one.
+        loop {
+            let (raw, _, _) = self.get_live(&key)?.ok_or(KvsError::KeyNotFound)?;
+            if tree
+                .compare_and_swap(&key, Some(raw), Some(new.clone()))?
+                .is_ok()
+            {
+                break self.wrote(len);
+            }
+        }
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> error::Result<()> {
+        let mut sled_batch = Batch::default();
+        let mut len = 0;
+        for entry in batch.entries {
+            len += entry.key.len();
+            match entry.value {
+                Some(value) => {
+                    let value = encode(&value, None);
+                    len += value.len();
+                    sled_batch.insert(entry.key, value)
+                }
+                None => sled_batch.remove(entry.key),
+            }
+        }
+
+        let _writes = self.writes.read().unwrap();
+        let tree: &Tree = &self.db;
+        tree.apply_batch(sled_batch)?;
+        self.wrote(len)
+    }
+
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> error::Result<Self::Scan> {
+        let tree: &Tree = &self.db;
+        Ok(SledScan {
+            iter: tree.range(range),
+            now: self.now(),
+        })
+    }
+
+    fn scan_prefix(&self, prefix: impl Into<Vec<u8>>) -> error::Result<Self::Scan> {
+        let tree: &Tree = &self.db;
+        Ok(SledScan {
+            iter: tree.scan_prefix(prefix.into()),
+            now: self.now(),
+        })
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> error::Result<()> {
+        let expires_at = clock::expiry(self.clock.now(), ttl);
+        self.insert(key.into(), value.into(), expires_at)
+    }
+
+    fn expire_at(&self, key: impl Into<Vec<u8>>, at: SystemTime) -> error::Result<()> {
+        let key = key.into();
+        let _writes = self.writes.read().unwrap();
+        let tree: &Tree = &self.db;
+        // Retry until the value is not overwritten between reading it and
+        // rewriting it with the new expiry.
+        loop {
+            let (raw, value, _) = self.get_live(&key)?.ok_or(KvsError::KeyNotFound)?;
+            let new = encode(&value, Some(clock::to_millis(at)));
+            let len = key.len() + new.len();
+            if tree.compare_and_swap(&key, Some(raw), Some(new))?.is_ok() {
+                break self.wrote(len);
+            }
+        }
+    }
+
+    fn ttl(&self, key: impl Into<Vec<u8>>) -> error::Result<Option<Duration>> {
+        let (_, _, expires_at) = self.get_live(&key.into())?.ok_or(KvsError::KeyNotFound)?;
+        Ok(clock::remaining(expires_at, self.now()))
+    }
+}
+
+/// Removes every key which expired by `now` from `db`, unless it is
+/// overwritten concurrently.
+///
+/// The removals are left to sled's own flushes: losing one to a crash only
+/// leaves behind a key which reads as missing anyway.
+fn sweep(db: &Db, now: u64, writes: &RwLock<()>) -> error::Result<()> {
+    let tree: &Tree = db;
+    let mut swept = 0;
+    for item in tree.iter() {
+        let (key, raw) = item?;
+        let (_, expires_at) = decode(&raw)?;
+        if clock::is_expired(expires_at, now) {
+            let _writes = writes.read().unwrap();
+            if tree
+                .compare_and_swap(&key, Some(raw), None as Option<IVec>)?
+                .is_ok()
+            {
+                swept += 1;
+            }
+        }
+    }
+    if swept > 0 {
+        debug!("Swept {} expired keys", swept);
+    }
+    Ok(())
+}
+
+/// Tags every value in `db` as never expiring, unless its metadata records
+/// that this was already done.
+///
+/// Each chunk of values is tagged in the same transaction that moves the
+/// cursor in the metadata past it, so no value is tagged twice.
+fn tag_values(db: &Db) -> error::Result<()> {
+    let meta = db.open_tree(META_TREE)?;
+    if meta.contains_key(TAGGED_KEY)? {
+        return Ok(());
+    }
+
+    let tree: &Tree = db;
+    loop {
+        let chunk = match meta.get(TAG_CURSOR_KEY)? {
+            Some(cursor) => tree.range::<&[u8], _>((Bound::Excluded(&*cursor), Bound::Unbounded)),
+            None => tree.iter(),
+        }
+        .take(TAG_CHUNK_SIZE)
+        .collect::<sled::Result<Vec<_>>>()?;
+
+        (tree, &meta)
+            .transaction(
+                |(tree, meta)| -> ConflictableTransactionResult<(), sled::Error> {
+                    for (key, value) in &chunk {
+                        tree.insert(key, encode(value, None))?;
+                    }
+                    match chunk.last() {
+                        Some((key, _)) => meta.insert(TAG_CURSOR_KEY, key)?,
+                        None => {
+                            meta.insert(TAGGED_KEY, &[])?;
+                            meta.remove(TAG_CURSOR_KEY)?
+                        }
+                    };
+                    Ok(())
+                },
+            )
+            .map_err(|e| match e {
+                TransactionError::Abort(e) | TransactionError::Storage(e) => e,
+            })?;
+        db.flush()?;
+
+        if chunk.is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+fn encode(value: &[u8], expires_at: Option<u64>) -> Vec<u8> {
+    let mut byte_buf = Vec::with_capacity(value.len() + 9);
+    match expires_at {
+        Some(expires_at) => {
+            byte_buf.push(EXPIRING);
+            byte_buf.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        None => byte_buf.push(PERSISTENT),
+    }
+    byte_buf.extend_from_slice(value);
+    byte_buf
+}
+
+fn decode(raw: &[u8]) -> error::Result<(Vec<u8>, Option<u64>)> {
+    match raw.split_first() {
+        Some((&PERSISTENT, value)) => Ok((value.to_vec(), None)),
+        Some((&EXPIRING, rest)) if rest.len() >= 8 => {
+            let (expiry_bytes, value) = rest.split_at(8);
+            let expires_at = u64::from_le_bytes(expiry_bytes.try_into()?);
+            Ok((value.to_vec(), Some(expires_at)))
+        }
+        _ => Err(KvsError::Unexpectedcommandtype),
+    }
+}
+
+/// An iterator over a range of a `SledKvsEngine`.
+///
+/// Keys which had expired when the scan was created are skipped.
+pub struct SledScan {
+    iter: Iter,
+    now: u64,
+}
+
+impl SledScan {
+    fn convert(&self, item: sled::Result<(IVec, IVec)>) -> Option<error::Result<KvPair>> {
+        let (key, raw) = match item {
+            Ok(item) => item,
+            Err(e) => return Some(Err(e.into())),
+        };
+        match decode(&raw) {
+            Ok((value, expires_at)) if !clock::is_expired(expires_at, self.now) => {
+                Some(Ok((key.to_vec(), value)))
+            }
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl Iterator for SledScan {
+    type Item = error::Result<KvPair>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(item) = self.iter.next() {
+            if let Some(pair) = self.convert(item) {
+                return Some(pair);
+            }
+        }
+        None
+    }
+}
+
+impl DoubleEndedIterator for SledScan {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while let Some(item) = self.iter.next_back() {
+            if let Some(pair) = self.convert(item) {
+                return Some(pair);
+            }
+        }
+        None
+    }
 }
@@ -0,0 +1,158 @@
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::KvsError;
+
+/// Decides when an engine syncs its writes to disk.
+///
+/// Writes are always handed to the operating system before they are
+/// acknowledged; this only controls whether they are also synced to the
+/// underlying storage, and so whether they survive a power loss.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync after every write.
+    Always,
+    /// Sync at most this many milliseconds after a write.
+    EveryMillis(u64),
+    /// Sync once this many bytes have been written since the last sync.
+    EveryBytes(u64),
+    /// Leave syncing to the operating system, or to sled's own background
+    /// flushes.
+    Never,
+}
+
+impl SyncPolicy {
+    /// Returns how often a background thread should sync pending writes, if
+    /// the policy needs one.
+    pub(crate) fn interval(self) -> Option<Duration> {
+        match self {
+            SyncPolicy::EveryMillis(millis) => Some(Duration::from_millis(millis.max(1))),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for SyncPolicy {
+    type Err = KvsError;
+
+    /// Parses `always`, `never`, `every-millis:<n>` or `every-bytes:<n>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || KvsError::String(format!("Invalid sync policy: {}", s));
+        match s {
+            "always" => return Ok(SyncPolicy::Always),
+            "never" => return Ok(SyncPolicy::Never),
+            _ => {}
+        }
+
+        let (kind, n) = s.split_once(':').ok_or_else(invalid)?;
+        let n = n.parse().map_err(|_| invalid())?;
+        match kind {
+            "every-millis" => Ok(SyncPolicy::EveryMillis(n)),
+            "every-bytes" => Ok(SyncPolicy::EveryBytes(n)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Tracks the writes which have not been synced yet, and decides when the
+/// policy calls for a sync.
+#[derive(Debug)]
+pub(crate) struct SyncState {
+    policy: SyncPolicy,
+    unsynced: u64,
+    last_sync: Instant,
+}
+
+impl SyncState {
+    pub fn new(policy: SyncPolicy) -> Self {
+        Self {
+            policy,
+            unsynced: 0,
+            last_sync: Instant::now(),
+        }
+    }
+
+    /// Records a write of `bytes`, returning `true` if it should be synced
+    /// before it is acknowledged.
+    pub fn wrote(&mut self, bytes: u64) -> bool {
+        self.unsynced += bytes;
+        match self.policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryMillis(millis) => {
+                self.last_sync.elapsed() >= Duration::from_millis(millis)
+            }
+            SyncPolicy::EveryBytes(bytes) => self.unsynced >= bytes,
+            SyncPolicy::Never => false,
+        }
+    }
+
+    /// Returns `true` if there are writes which the policy still owes a sync.
+    pub fn is_dirty(&self) -> bool {
+        self.policy != SyncPolicy::Never && self.unsynced > 0
+    }
+
+    /// Records that every write so far has been synced.
+    pub fn synced(&mut self) {
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+    }
+}
+
+/// A thread which periodically syncs pending writes for
+/// `SyncPolicy::EveryMillis`, or runs other periodic upkeep.
+///
+/// The thread is stopped when the `Syncer` is dropped.
+pub(crate) struct Syncer {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Syncer {
+    /// Spawns a thread called `name` which calls `sync` every `interval`.
+    pub fn spawn(name: &str, interval: Duration, mut sync: impl FnMut() + Send + 'static) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::Builder::new()
+            .name(String::from(name))
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    sync();
+                }
+            })
+            .expect("Cannot spawn syncer thread");
+
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            handle.join().expect("Syncer thread panicked");
+        }
+    }
+}
+
+/// Syncs a directory, so that files created in, renamed into or removed from
+/// it survive a power loss.
+#[cfg(unix)]
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    std::fs::File::open(dir)?.sync_all()
+}
+
+/// Syncs a directory, so that files created in, renamed into or removed from
+/// it survive a power loss.
+///
+/// Windows cannot sync a directory, and makes its metadata durable on its
+/// own.
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
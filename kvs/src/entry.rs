@@ -1,14 +1,187 @@
 use std::convert::TryInto;
-use std::io::{Read, Seek, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crc32fast::Hasher;
+use flate2::read::{DeflateDecoder, DeflateEncoder};
 
-use crate::Result;
+use crate::encryption::{self, EncryptionKey};
+use crate::{KvsError, Result};
 
 /// The size of the entry's prefix in bytes.
-pub const PREFIX_SIZE: usize = 12;
+pub const PREFIX_SIZE: usize = 14;
 
-type Value = Option<String>;
+/// The size of the entry's prefix in bytes, in logs of version 1 and 2.
+pub const V2_PREFIX_SIZE: usize = 13;
+
+/// The size of the entry's prefix in bytes, in logs without a header.
+pub const LEGACY_PREFIX_SIZE: usize = 12;
+
+/// The magic number which starts every log file with a header.
+pub const MAGIC: [u8; 4] = *b"KVS\0";
+
+/// The size of a log file's header in bytes.
+pub const HEADER_SIZE: usize = 62;
+
+/// The size of a log file's header in bytes, in logs of version 2 and 3.
+const V3_HEADER_SIZE: usize = 21;
+
+/// The size of a log file's header in bytes, in logs of version 1.
+const V1_HEADER_SIZE: usize = 5;
+
+/// A version of the log format.
+pub type Version = u8;
+
+/// The version of logs written before log files had a header.
+///
+/// Entries in these logs have no kind; a tombstone is an entry with an empty
+/// value.
+pub const LEGACY_VERSION: Version = 0;
+
+/// The first version of logs with a header, which only holds the magic
+/// number and version.
+///
+/// Entries in these logs store their checksum big-endian and their sizes in
+/// the byte order of the machine which wrote them.
+pub const V1_VERSION: Version = 1;
+
+/// The second version of logs with a header.
+///
+/// The header also holds the time the log was created and its generation,
+/// and every field is little-endian.
+pub const V2_VERSION: Version = 2;
+
+/// The third version of logs with a header.
+///
+/// Entries also record the codec their value was compressed with.
+pub const V3_VERSION: Version = 3;
+
+/// The version of the log format written by this crate.
+///
+/// The header also records whether the log is encrypted, along with a check
+/// of the key it was encrypted with. Entries in an encrypted log have their
+/// key and value sealed with that key, along with their generation and
+/// offset, and keep their CRC32 so that torn writes can be told apart without
+/// it.
+pub const CURRENT_VERSION: Version = 4;
+
+/// The header of a log file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// The version of the log format.
+    pub version: Version,
+    /// When the log was created, in seconds since the Unix epoch, if the
+    /// format records it.
+    pub created: Option<u64>,
+    /// The generation of the log, if the format records it.
+    pub gen: Option<u64>,
+    /// Proof of the key the log was encrypted with, if it is encrypted.
+    pub key_check: Option<[u8; encryption::SEAL_OVERHEAD]>,
+}
+
+type Value = Option<Vec<u8>>;
+
+/// The kind of an entry, which decides how it is replayed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    /// Sets a key to a value.
+    Put = 0,
+    /// Removes a key.
+    Delete = 1,
+    /// Groups entries which are applied atomically.
+    ///
+    /// The value of a batch record holds its entries, each encoded as if it
+    /// stood alone in the log.
+    Batch = 2,
+    /// Sets a key to a value until a point in time.
+    ///
+    /// The value starts with the expiry time, in milliseconds since the Unix
+    /// epoch as a little-endian `u64`.
+    ExpiringPut = 3,
+}
+
+impl EntryKind {
+    fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0 => Ok(EntryKind::Put),
+            1 => Ok(EntryKind::Delete),
+            2 => Ok(EntryKind::Batch),
+            3 => Ok(EntryKind::ExpiringPut),
+            _ => Err(KvsError::Unexpectedcommandtype),
+        }
+    }
+}
+
+/// A codec which compresses the values of entries in the log.
+///
+/// Only an entry's value is compressed; its key and expiry are kept as they
+/// are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    /// Values are stored uncompressed.
+    None = 0,
+    /// Values are compressed with Snappy, which is fast but compresses less.
+    Snappy = 1,
+    /// Values are compressed with Deflate, which compresses more but is
+    /// slower.
+    Deflate = 2,
+}
+
+impl Codec {
+    fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Snappy),
+            2 => Ok(Codec::Deflate),
+            _ => Err(KvsError::UnsupportedCodec { codec: byte }),
+        }
+    }
+
+    fn compress(self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(bytes.to_vec()),
+            Codec::Snappy => snap::raw::Encoder::new()
+                .compress_vec(bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e).into()),
+            Codec::Deflate => {
+                let mut compressed = vec![];
+                DeflateEncoder::new(bytes, flate2::Compression::default())
+                    .read_to_end(&mut compressed)?;
+                Ok(compressed)
+            }
+        }
+    }
+
+    fn decompress(self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(bytes.to_vec()),
+            Codec::Snappy => snap::raw::Decoder::new()
+                .decompress_vec(bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into()),
+            Codec::Deflate => {
+                let mut value = vec![];
+                DeflateDecoder::new(bytes).read_to_end(&mut value)?;
+                Ok(value)
+            }
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = KvsError;
+
+    /// Parses `none`, `snappy` or `deflate`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Codec::None),
+            "snappy" => Ok(Codec::Snappy),
+            "deflate" => Ok(Codec::Deflate),
+            _ => Err(KvsError::String(format!("Invalid codec: {}", s))),
+        }
+    }
+}
 
 /// An entry in the log which represents adding or removing keys and values.
 ///
@@ -18,12 +191,12 @@ type Value = Option<String>;
 #[derive(Debug)]
 pub struct Entry {
     /// The key of the entry.
-    pub key: String,
-    /// The value of the entry.
+    pub key: Vec<u8>,
+    /// The value of the entry, or `None` for a removal.
     pub value: Value,
-    crc32: u32,
-    key_size: u32,
-    value_size: u32,
+    /// When the value expires, in milliseconds since the Unix epoch, or
+    /// `None` if it never does.
+    pub expires_at: Option<u64>,
 }
 
 impl Entry {
@@ -36,8 +209,33 @@ impl Entry {
     ///
     /// let entry = Entry::set("foo", "bar");
     /// ```
-    pub fn set(key: impl Into<String>, value: impl Into<String>) -> Self {
-        Entry::new(key.into(), Some(value.into()))
+    pub fn set(key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Self {
+        Entry {
+            key: key.into(),
+            value: Some(value.into()),
+            expires_at: None,
+        }
+    }
+
+    /// Create an set entry for a key-value pair which expires at a point in
+    /// time, given in milliseconds since the Unix epoch.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::Entry;
+    ///
+    /// let entry = Entry::set_expiring("foo", "bar", 1_600_000_000_000);
+    /// ```
+    pub fn set_expiring(
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        expires_at: u64,
+    ) -> Self {
+        Entry {
+            expires_at: Some(expires_at),
+            ..Entry::set(key, value)
+        }
     }
 
     /// Create an removal entry for a key.
@@ -49,106 +247,455 @@ impl Entry {
     ///
     /// let entry = Entry::remove("foo");
     /// ```
-    pub fn remove(key: impl Into<String>) -> Self {
-        // `None` serves as our tombstone value.
-        Entry::new(key.into(), None)
+    pub fn remove(key: impl Into<Vec<u8>>) -> Self {
+        Entry {
+            key: key.into(),
+            value: None,
+            expires_at: None,
+        }
+    }
+
+    /// Returns the kind of the entry.
+    pub fn kind(&self) -> EntryKind {
+        match (&self.value, self.expires_at) {
+            (Some(_), None) => EntryKind::Put,
+            (Some(_), Some(_)) => EntryKind::ExpiringPut,
+            (None, _) => EntryKind::Delete,
+        }
     }
 
     /// Returns a byte buffer of the entry's properties, with the CRC32
     /// occupying the first 4 bytes.
     pub fn as_durable_bytes(&self) -> Vec<u8> {
-        let mut byte_buf = vec![];
-        byte_buf.extend_from_slice(&self.crc32.to_be_bytes());
-        byte_buf.extend_from_slice(&self.as_bytes());
-        byte_buf.to_vec()
+        let value = self.value.as_deref().unwrap_or_default();
+        with_crc32(self.as_bytes(Codec::None, value.to_vec(), None, 0, 0))
     }
 
-    /// Returns a byte buffer of the entry's properties, without the CRC32.
-    fn as_bytes(&self) -> Vec<u8> {
-        as_bytes(self.key_size, self.value_size, &self.key, &self.value)
+    /// Returns a byte buffer of the entry's properties, with the CRC32
+    /// occupying the first 4 bytes and the value compressed with `codec`.
+    ///
+    /// Values shorter than `threshold` bytes, or which do not get any smaller
+    /// when compressed, are stored uncompressed.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the codec cannot compress the value, such as when it is too
+    /// large for Snappy.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::{Codec, Entry};
+    ///
+    /// let entry = Entry::set("foo", "bar".repeat(100));
+    /// let bytes = entry.as_compressed_durable_bytes(Codec::Snappy, 64).unwrap();
+    /// assert!(bytes.len() < entry.as_durable_bytes().len());
+    /// ```
+    pub fn as_compressed_durable_bytes(&self, codec: Codec, threshold: usize) -> Result<Vec<u8>> {
+        self.encode(codec, threshold, None, 0, 0)
     }
 
-    fn new(key: String, value: Value) -> Self {
-        let key_size = key.len() as u32;
+    /// Returns a byte buffer of the entry's properties as they are written
+    /// to a log, with the CRC32 occupying the first 4 bytes, the value
+    /// compressed as by [`as_compressed_durable_bytes`], and the key and
+    /// value sealed with `key` if there is one.
+    ///
+    /// A sealed entry can only be read back at `offset` in the log of
+    /// generation `gen`.
+    ///
+    /// [`as_compressed_durable_bytes`]: #method.as_compressed_durable_bytes
+    pub(crate) fn encode(
+        &self,
+        codec: Codec,
+        threshold: usize,
+        key: Option<&EncryptionKey>,
+        gen: u64,
+        offset: u64,
+    ) -> Result<Vec<u8>> {
+        let (codec, value_bytes) = self.compressed_value(codec, threshold)?;
+        Ok(with_crc32(self.as_bytes(
+            codec,
+            value_bytes,
+            key,
+            gen,
+            offset,
+        )))
+    }
 
-        let mut value_size = 0;
-        if let Some(ref v) = value {
-            value_size = v.len() as u32;
-        }
+    /// Returns the entry's value compressed with `codec`, along with the codec
+    /// it ends up stored with.
+    fn compressed_value(&self, codec: Codec, threshold: usize) -> Result<(Codec, Vec<u8>)> {
+        let value = self.value.as_deref().unwrap_or_default();
+        Ok(match codec {
+            Codec::None => (Codec::None, value.to_vec()),
+            _ if value.len() < threshold => (Codec::None, value.to_vec()),
+            _ => match codec.compress(value)? {
+                compressed if compressed.len() < value.len() => (codec, compressed),
+                _ => (Codec::None, value.to_vec()),
+            },
+        })
+    }
 
-        let crc32 = generate_crc32(key_size, value_size, &key, &value);
+    /// Returns a byte buffer of the entry's properties, without the CRC32,
+    /// holding `value_bytes` as its value compressed with `codec`.
+    fn as_bytes(
+        &self,
+        codec: Codec,
+        value_bytes: Vec<u8>,
+        key: Option<&EncryptionKey>,
+        gen: u64,
+        offset: u64,
+    ) -> Vec<u8> {
+        let expiry_bytes = match self.kind() {
+            EntryKind::ExpiringPut => self.expires_at.unwrap_or_default().to_le_bytes().to_vec(),
+            _ => vec![],
+        };
 
-        Self {
-            key,
-            value,
-            crc32,
-            key_size,
-            value_size,
+        let mut byte_buf = vec![];
+        byte_buf.push(self.kind() as u8);
+        byte_buf.push(codec as u8);
+        byte_buf.extend_from_slice(&(self.key.len() as u32).to_le_bytes());
+        byte_buf
+            .extend_from_slice(&((expiry_bytes.len() + value_bytes.len()) as u32).to_le_bytes());
+
+        let mut body = self.key.clone();
+        body.extend_from_slice(&expiry_bytes);
+        body.extend_from_slice(&value_bytes);
+        match key {
+            Some(key) => {
+                let sealed = key.seal(&sealed_aad(gen, offset, &byte_buf), &body);
+                byte_buf.extend_from_slice(&sealed);
+            }
+            None => byte_buf.extend_from_slice(&body),
         }
+        byte_buf
     }
 }
 
-fn generate_crc32(key_size: u32, value_size: u32, key: &str, value: &Value) -> u32 {
-    let mut crc_hasher = Hasher::new();
-    crc_hasher.update(&as_bytes(key_size, value_size, &key, &value));
-    crc_hasher.finalize()
+/// Returns the data authenticated along with the sealed body of an entry at
+/// `offset` in the log of generation `gen`, whose prefix after the CRC32 is
+/// `prefix`.
+///
+/// The prefix cannot be changed without the key, and neither can the entry
+/// be moved to another place in the log or to another log.
+fn sealed_aad(gen: u64, offset: u64, prefix: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(16 + prefix.len());
+    aad.extend_from_slice(&gen.to_le_bytes());
+    aad.extend_from_slice(&offset.to_le_bytes());
+    aad.extend_from_slice(prefix);
+    aad
 }
 
-fn as_bytes(key_size: u32, value_size: u32, key: &str, value: &Value) -> Vec<u8> {
+/// Returns `bytes` preceded by their CRC32.
+fn with_crc32(bytes: Vec<u8>) -> Vec<u8> {
+    let mut crc_hasher = Hasher::new();
+    crc_hasher.update(&bytes);
+
     let mut byte_buf = vec![];
+    byte_buf.extend_from_slice(&crc_hasher.finalize().to_le_bytes());
+    byte_buf.extend_from_slice(&bytes);
+    byte_buf
+}
 
-    byte_buf.extend_from_slice(&key_size.to_ne_bytes());
-    byte_buf.extend_from_slice(&value_size.to_ne_bytes());
-    byte_buf.extend_from_slice(&key.as_bytes());
+/// Write the header of a new log file for generation `gen` to given writer,
+/// recording that the log is encrypted with `key` if there is one.
+pub fn write_header<W>(writer: &mut W, gen: u64, key: Option<&EncryptionKey>) -> Result<()>
+where
+    W: Write,
+{
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
 
-    let mut value_bytes: &[u8] = &[];
-    if let Some(ref v) = value {
-        value_bytes = v.as_bytes();
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&[CURRENT_VERSION])?;
+    writer.write_all(&created.to_le_bytes())?;
+    writer.write_all(&gen.to_le_bytes())?;
+    match key {
+        Some(key) => {
+            writer.write_all(&[1])?;
+            writer.write_all(&key.key_check())?;
+        }
+        None => writer.write_all(&[0; 1 + encryption::SEAL_OVERHEAD])?,
     }
+    Ok(())
+}
 
-    byte_buf.extend_from_slice(value_bytes);
+/// Read the header of a log file from given reader.
+///
+/// Logs which do not start with a header are written in the legacy format;
+/// the reader is left at the start of the file for them.
+///
+/// # Errors
+///
+/// Returns `KvsError::UnsupportedVersion` if the log was written in a newer
+/// format than this crate understands.
+pub fn read_header<R>(reader: &mut R) -> Result<Header>
+where
+    R: Read + Seek,
+{
+    let mut header_bytes = vec![];
+    reader
+        .take(V1_HEADER_SIZE as u64)
+        .read_to_end(&mut header_bytes)?;
 
-    byte_buf
+    if header_bytes.len() < V1_HEADER_SIZE || header_bytes[..MAGIC.len()] != MAGIC {
+        reader.seek(SeekFrom::Start(0))?;
+        return Ok(Header {
+            version: LEGACY_VERSION,
+            created: None,
+            gen: None,
+            key_check: None,
+        });
+    }
+
+    let version = header_bytes[MAGIC.len()];
+    let rest_size = match version {
+        V1_VERSION => 0,
+        V2_VERSION | V3_VERSION => V3_HEADER_SIZE - V1_HEADER_SIZE,
+        CURRENT_VERSION => HEADER_SIZE - V1_HEADER_SIZE,
+        _ => return Err(KvsError::UnsupportedVersion { version }),
+    };
+    let mut rest = vec![0; rest_size];
+    reader.read_exact(&mut rest)?;
+    if version == V1_VERSION {
+        return Ok(Header {
+            version,
+            created: None,
+            gen: None,
+            key_check: None,
+        });
+    }
+
+    let key_check = match rest.get(16) {
+        Some(1) => Some(rest[17..].try_into()?),
+        _ => None,
+    };
+    Ok(Header {
+        version,
+        created: Some(u64::from_le_bytes(rest[..8].try_into()?)),
+        gen: Some(u64::from_le_bytes(rest[8..16].try_into()?)),
+        key_check,
+    })
+}
+
+/// A record in the log: a single entry, or a batch of them.
+#[derive(Debug)]
+pub enum Record {
+    /// A single entry.
+    Entry(Entry),
+    /// The entries of a batch, each with its range of bytes relative to the
+    /// start of the record.
+    Batch(Vec<(Range<u64>, Entry)>),
 }
 
-/// Write Entry to given writer.
-pub fn to_writer<W>(writer: &mut W, entry: &Entry) -> Result<()>
+/// Returns a byte buffer of a batch record holding `entries`, along with
+/// the range of each entry relative to the start of the record.
+///
+/// A single CRC32 covers the whole batch, so a batch which was only partly
+/// written fails its checksum as a whole. Each entry's value is compressed
+/// as by [`Entry::as_compressed_durable_bytes`], and each entry is sealed
+/// with `key` if there is one, for a record at `offset` in the log of
+/// generation `gen`; the batch record itself is not.
+pub fn batch_as_durable_bytes(
+    entries: &[Entry],
+    codec: Codec,
+    threshold: usize,
+    key: Option<&EncryptionKey>,
+    gen: u64,
+    offset: u64,
+) -> Result<(Vec<u8>, Vec<Range<u64>>)> {
+    let mut payload = vec![];
+    let mut ranges = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let start = (PREFIX_SIZE + payload.len()) as u64;
+        payload.extend_from_slice(&entry.encode(codec, threshold, key, gen, offset + start)?);
+        ranges.push(start..(PREFIX_SIZE + payload.len()) as u64);
+    }
+
+    let mut bytes = vec![];
+    bytes.push(EntryKind::Batch as u8);
+    bytes.push(Codec::None as u8);
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    Ok((with_crc32(bytes), ranges))
+}
+
+/// Write Entry to given writer, compressing its value with `codec` unless it
+/// is shorter than `threshold` bytes, and sealing it with `key` if there is
+/// one for `offset` in the log of generation `gen`, where the writer is.
+pub fn to_writer<W>(
+    writer: &mut W,
+    entry: &Entry,
+    codec: Codec,
+    threshold: usize,
+    key: Option<&EncryptionKey>,
+    gen: u64,
+    offset: u64,
+) -> Result<()>
 where
     W: Write + Seek,
 {
-    writer.write_all(&entry.as_durable_bytes())?;
+    writer.write_all(&entry.encode(codec, threshold, key, gen, offset)?)?;
     Ok(())
 }
 
 /// Read to a new Entry from given reader.
-pub fn from_reader(reader: &mut dyn Read) -> Result<Entry> {
+///
+/// # Errors
+///
+/// Returns `KvsError::Unexpectedcommandtype` if the reader holds a batch
+/// record rather than a single entry, and otherwise fails as
+/// [`record_from_reader`] does.
+pub fn from_reader(
+    reader: &mut dyn Read,
+    version: Version,
+    gen: u64,
+    offset: u64,
+    key: Option<&EncryptionKey>,
+) -> Result<Entry> {
+    match record_from_reader(reader, version, gen, offset, key)? {
+        Record::Entry(entry) => Ok(entry),
+        Record::Batch(_) => Err(KvsError::Unexpectedcommandtype),
+    }
+}
+
+/// Read to a new Record from given reader.
+///
+/// The record is read in the log format `version`, and is expected at
+/// `offset` in the log of generation `gen`; these describe where a corrupt
+/// entry was found, and a sealed entry only unseals where it was written.
+/// `key` must be the key the log is encrypted with, or `None` if it is not
+/// encrypted.
+///
+/// # Errors
+///
+/// Returns an `UnexpectedEof` IO error if the reader ends before the record
+/// does, `KvsError::Corruption` if the record's contents do not match its
+/// CRC32 or are too short for its kind, `KvsError::Authentication` if they do but cannot be unsealed with
+/// `key`, and `KvsError::UnsupportedCodec` if its value was compressed with
+/// a codec this crate does not know.
+pub fn record_from_reader(
+    reader: &mut dyn Read,
+    version: Version,
+    gen: u64,
+    offset: u64,
+    key: Option<&EncryptionKey>,
+) -> Result<Record> {
+    let prefix_size = match version {
+        LEGACY_VERSION => LEGACY_PREFIX_SIZE,
+        V1_VERSION | V2_VERSION => V2_PREFIX_SIZE,
+        _ => PREFIX_SIZE,
+    };
+
     let mut prefix_bytes = [0; PREFIX_SIZE];
-    reader.read_exact(&mut prefix_bytes)?;
+    let prefix_bytes = &mut prefix_bytes[..prefix_size];
+    reader.read_exact(prefix_bytes)?;
+
+    let crc_bytes = prefix_bytes[..4].try_into()?;
+    let key_size_bytes = prefix_bytes[prefix_size - 8..prefix_size - 4].try_into()?;
+    let value_size_bytes = prefix_bytes[prefix_size - 4..].try_into()?;
+    let (crc32, key_size, value_size) = match version {
+        LEGACY_VERSION | V1_VERSION => (
+            u32::from_be_bytes(crc_bytes),
+            u32::from_ne_bytes(key_size_bytes),
+            u32::from_ne_bytes(value_size_bytes),
+        ),
+        _ => (
+            u32::from_le_bytes(crc_bytes),
+            u32::from_le_bytes(key_size_bytes),
+            u32::from_le_bytes(value_size_bytes),
+        ),
+    };
 
-    let crc32 = u32::from_be_bytes(prefix_bytes[..4].try_into()?);
-    let key_size = u32::from_ne_bytes(prefix_bytes[4..8].try_into()?);
-    let value_size = u32::from_ne_bytes(prefix_bytes[8..PREFIX_SIZE].try_into()?);
+    // Batch records are not sealed themselves, only the entries they hold.
+    let seal_key =
+        key.filter(|_| version > V3_VERSION && prefix_bytes[4] != EntryKind::Batch as u8);
+    let seal_overhead = match seal_key {
+        Some(_) => encryption::SEAL_OVERHEAD as u64,
+        None => 0,
+    };
 
-    let mut bytes: Vec<u8> = vec![0; (key_size + value_size) as usize];
-    reader.read_exact(&mut bytes)?;
+    // The sizes are not covered by a checksum until the whole entry is read,
+    // so don't trust them with an up-front allocation.
+    let len = u64::from(key_size) + u64::from(value_size) + seal_overhead;
+    let mut bytes: Vec<u8> = vec![];
+    reader.take(len).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
 
-    let key_offset = key_size as usize;
-    let value_offset = key_offset + value_size as usize;
-    let key = String::from_utf8(bytes[..key_offset].to_vec())?;
-    let value = String::from_utf8(bytes[key_offset..value_offset].to_vec())?;
+    let mut crc_hasher = Hasher::new();
+    crc_hasher.update(&prefix_bytes[4..]);
+    crc_hasher.update(&bytes);
+    let actual = crc_hasher.finalize();
+    if crc32 != actual {
+        return Err(KvsError::Corruption {
+            gen,
+            offset,
+            expected: crc32,
+            actual,
+        });
+    }
+    if let Some(seal_key) = seal_key {
+        bytes = seal_key
+            .open(&sealed_aad(gen, offset, &prefix_bytes[4..]), &bytes)
+            .ok_or(KvsError::Authentication { gen, offset })?;
+    }
 
-    let value: Value = match value.len() {
-        0 => None,
-        _ => Some(value),
+    let kind = match version {
+        LEGACY_VERSION if value_size == 0 => EntryKind::Delete,
+        LEGACY_VERSION => EntryKind::Put,
+        _ => EntryKind::from_byte(prefix_bytes[4])?,
+    };
+    let codec = match version {
+        LEGACY_VERSION | V1_VERSION | V2_VERSION => Codec::None,
+        _ => Codec::from_byte(prefix_bytes[5])?,
     };
 
-    assert_eq!(crc32, generate_crc32(key_size, value_size, &key, &value));
+    let mut expires_at = None;
+    let value = match kind {
+        EntryKind::Put => Some(codec.decompress(&bytes.split_off(key_size as usize))?),
+        EntryKind::ExpiringPut => {
+            // The checksum matched, so the entry was written this short.
+            if value_size < 8 {
+                return Err(KvsError::Corruption {
+                    gen,
+                    offset,
+                    expected: crc32,
+                    actual,
+                });
+            }
+            let value = bytes.split_off(key_size as usize);
+            expires_at = Some(u64::from_le_bytes(value[..8].try_into()?));
+            Some(codec.decompress(&value[8..])?)
+        }
+        EntryKind::Delete => {
+            bytes.truncate(key_size as usize);
+            None
+        }
+        EntryKind::Batch => {
+            let mut entries = vec![];
+            let mut rest = &bytes[key_size as usize..];
+            // The entries follow the batch's prefix, which is as long as the
+            // log's format makes it.
+            let mut pos = prefix_size as u64 + u64::from(key_size);
+            while !rest.is_empty() {
+                let len = rest.len();
+                let entry = from_reader(&mut rest, version, gen, offset + pos, key)?;
+                let end = pos + (len - rest.len()) as u64;
+                entries.push((pos..end, entry));
+                pos = end;
+            }
+            return Ok(Record::Batch(entries));
+        }
+    };
+    let key = bytes;
 
-    Ok(Entry {
-        crc32,
+    Ok(Record::Entry(Entry {
         key,
-        key_size,
         value,
-        value_size,
-    })
+        expires_at,
+    }))
 }
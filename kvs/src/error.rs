@@ -1,3 +1,6 @@
+// `failure_derive` expands to impls inside a named const.
+#![allow(non_local_definitions)]
+
 use std::io;
 
 /// Result type for kvs.
@@ -18,6 +21,77 @@ pub enum KvsError {
     #[fail(display = "{}", _0)]
     TryFromSlice(#[cause] std::array::TryFromSliceError),
 
+    /// An entry's contents do not match its checksum, or are too short for
+    /// its kind.
+    ///
+    /// This indicates data corruption on disk.
+    #[fail(
+        display = "Corrupt entry in generation {} at offset {}: expected checksum {:#010x}, got {:#010x}",
+        gen, offset, expected, actual
+    )]
+    Corruption {
+        /// The generation of the log holding the entry.
+        gen: u64,
+        /// The offset of the entry within its log.
+        offset: u64,
+        /// The checksum stored with the entry.
+        expected: u32,
+        /// The checksum of the entry's contents.
+        actual: u32,
+    },
+
+    /// A log was written in a newer format than this crate understands.
+    #[fail(
+        display = "Unsupported log format version {}; upgrade kvs to open this store",
+        version
+    )]
+    UnsupportedVersion {
+        /// The version of the log's format.
+        version: u8,
+    },
+
+    /// An entry matches its checksum, but cannot be unsealed with the
+    /// store's encryption key.
+    ///
+    /// This indicates the log was tampered with.
+    #[fail(
+        display = "Entry in generation {} at offset {} failed authentication",
+        gen, offset
+    )]
+    Authentication {
+        /// The generation of the log holding the entry.
+        gen: u64,
+        /// The offset of the entry within its log.
+        offset: u64,
+    },
+
+    /// A log was encrypted with a different key than the one given.
+    #[fail(
+        display = "Wrong encryption key: generation {} was encrypted with another key",
+        gen
+    )]
+    WrongKey {
+        /// The generation of the log.
+        gen: u64,
+    },
+
+    /// A log is encrypted, but no encryption key was given.
+    #[fail(
+        display = "Generation {} is encrypted, but no encryption key was given",
+        gen
+    )]
+    MissingKey {
+        /// The generation of the log.
+        gen: u64,
+    },
+
+    /// An entry's value was compressed with a codec this crate does not know.
+    #[fail(display = "Unsupported compression codec {}", codec)]
+    UnsupportedCodec {
+        /// The id of the codec.
+        codec: u8,
+    },
+
     /// Removing non-existent key error.
     #[fail(display = "Key not found")]
     KeyNotFound,
@@ -28,6 +102,31 @@ pub enum KvsError {
     #[fail(display = "Unexpected command type")]
     Unexpectedcommandtype,
 
+    /// A transaction read a key which was written to before it committed.
+    #[fail(display = "Transaction conflicts with a concurrent write")]
+    Conflict,
+
+    /// A conditional write found a different value than it expected.
+    #[fail(display = "Current value does not match the expected value")]
+    CompareAndSwap {
+        /// The value the key actually had, or `None` if it did not exist.
+        current: Option<Vec<u8>>,
+    },
+
+    /// Another store already holds the lock on the data directory.
+    ///
+    /// Only one store may write to a data directory at a time; others can
+    /// still open it read-only.
+    #[fail(display = "Data directory is locked by process {}", pid)]
+    Locked {
+        /// The id of the process holding the lock.
+        pid: u32,
+    },
+
+    /// Writing to a store which was opened read-only.
+    #[fail(display = "Store is read-only")]
+    ReadOnly,
+
     /// Sled error
     #[fail(display = "Sled error: {}", _0)]
     Sled(#[cause] sled::Error),
@@ -7,13 +7,23 @@ extern crate failure_derive;
 #[macro_use]
 extern crate log;
 
+pub use batch::WriteBatch;
 pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
-pub use entry::{from_reader, Entry};
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use encryption::EncryptionKey;
+pub use engines::{
+    backup, restore, verify, BackupStats, CompactionTrigger, CorruptRange, CorruptionPolicy,
+    KvPair, KvStore, KvStoreOptions, KvStoreScan, KvsEngine, SledKvsEngine, SledScan, Snapshot,
+    SyncPolicy, Transaction, VerifyReport,
+};
+pub use entry::{from_reader, record_from_reader, Codec, Entry, EntryKind, Record};
 pub use error::{KvsError, Result};
 pub use server::KvsServer;
 
+mod batch;
 mod client;
+mod clock;
+mod encryption;
 mod engines;
 mod entry;
 mod request;
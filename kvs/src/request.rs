@@ -1,44 +1,216 @@
-use std::io::BufRead;
+use std::io::{self, BufRead, Read, Write};
 
 use crate::error;
 use crate::KvsError;
 
+const GET: u8 = b'?';
+const SET: u8 = b'+';
+const REMOVE: u8 = b'-';
+const SET_WITH_TTL: u8 = b'*';
+const COMPARE_AND_SWAP: u8 = b'=';
+const SET_IF_ABSENT: u8 = b'<';
+const SET_IF_PRESENT: u8 = b'>';
+const TTL: u8 = b'~';
+const EXPIRE_AT: u8 = b'@';
+
+/// A request from a client to the server.
+///
+/// A request is a single byte naming the command, followed by its key and,
+/// for sets, its value. Each is sent as a little-endian `u32` length followed
+/// by that many bytes, so keys and values may hold arbitrary bytes. A time
+/// to live is sent as a little-endian `u64` count of milliseconds, and a
+/// point in time as one of milliseconds since the Unix epoch. A value which
+/// may be missing is preceded by a byte which is 1 if it is present and
+/// 0 if it is not.
 #[derive(Debug)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: Vec<u8>,
+    },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    SetWithTtl {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl_millis: u64,
+    },
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    SetIfAbsent {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    SetIfPresent {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Ttl {
+        key: Vec<u8>,
+    },
+    ExpireAt {
+        key: Vec<u8>,
+        at_millis: u64,
+    },
 }
 
 impl Request {
     pub fn from_reader(reader: &mut dyn BufRead) -> error::Result<Request> {
-        match reader.lines().next() {
-            Some(req) => {
-                let req = req?;
-                match req.as_str() {
-                    "?" => match reader.lines().next() {
-                        Some(key) => Ok(Request::Get { key: key? }),
-                        None => Err(KvsError::String(String::from("Malformed get request"))),
-                    },
-                    "+" => {
-                        let key = reader.lines().next();
-                        let value = reader.lines().next();
-                        match (key, value) {
-                            (Some(key), Some(value)) => Ok(Request::Set {
-                                key: key?,
-                                value: value?,
-                            }),
-                            _ => Err(KvsError::String(String::from("Malformed set request"))),
-                        }
-                    }
-                    "-" => match reader.lines().next() {
-                        Some(key) => Ok(Request::Remove { key: key? }),
-                        None => Err(KvsError::String(String::from("Malformed remove request"))),
-                    },
-                    _ => Err(KvsError::String(String::from("Illegal server command"))),
-                }
-            }
-            None => Err(KvsError::String(String::from("Malformed request"))),
+        let mut command = [0; 1];
+        reader
+            .read_exact(&mut command)
+            .map_err(|_| KvsError::String(String::from("Malformed request")))?;
+
+        match command[0] {
+            GET => Ok(Request::Get {
+                key: read_bytes(reader)?,
+            }),
+            SET => Ok(Request::Set {
+                key: read_bytes(reader)?,
+                value: read_bytes(reader)?,
+            }),
+            REMOVE => Ok(Request::Remove {
+                key: read_bytes(reader)?,
+            }),
+            SET_WITH_TTL => Ok(Request::SetWithTtl {
+                key: read_bytes(reader)?,
+                value: read_bytes(reader)?,
+                ttl_millis: read_u64(reader)?,
+            }),
+            COMPARE_AND_SWAP => Ok(Request::CompareAndSwap {
+                key: read_bytes(reader)?,
+                expected: read_optional_bytes(reader)?,
+                new: read_optional_bytes(reader)?,
+            }),
+            SET_IF_ABSENT => Ok(Request::SetIfAbsent {
+                key: read_bytes(reader)?,
+                value: read_bytes(reader)?,
+            }),
+            SET_IF_PRESENT => Ok(Request::SetIfPresent {
+                key: read_bytes(reader)?,
+                value: read_bytes(reader)?,
+            }),
+            TTL => Ok(Request::Ttl {
+                key: read_bytes(reader)?,
+            }),
+            EXPIRE_AT => Ok(Request::ExpireAt {
+                key: read_bytes(reader)?,
+                at_millis: read_u64(reader)?,
+            }),
+            _ => Err(KvsError::String(String::from("Illegal server command"))),
+        }
+    }
+
+    pub fn to_writer(&self, writer: &mut dyn Write) -> error::Result<()> {
+        match self {
+            Request::Get { key } => {
+                writer.write_all(&[GET])?;
+                write_bytes(writer, key)?;
+            }
+            Request::Set { key, value } => {
+                writer.write_all(&[SET])?;
+                write_bytes(writer, key)?;
+                write_bytes(writer, value)?;
+            }
+            Request::Remove { key } => {
+                writer.write_all(&[REMOVE])?;
+                write_bytes(writer, key)?;
+            }
+            Request::SetWithTtl {
+                key,
+                value,
+                ttl_millis,
+            } => {
+                writer.write_all(&[SET_WITH_TTL])?;
+                write_bytes(writer, key)?;
+                write_bytes(writer, value)?;
+                writer.write_all(&ttl_millis.to_le_bytes())?;
+            }
+            Request::CompareAndSwap { key, expected, new } => {
+                writer.write_all(&[COMPARE_AND_SWAP])?;
+                write_bytes(writer, key)?;
+                write_optional_bytes(writer, expected.as_deref())?;
+                write_optional_bytes(writer, new.as_deref())?;
+            }
+            Request::SetIfAbsent { key, value } => {
+                writer.write_all(&[SET_IF_ABSENT])?;
+                write_bytes(writer, key)?;
+                write_bytes(writer, value)?;
+            }
+            Request::SetIfPresent { key, value } => {
+                writer.write_all(&[SET_IF_PRESENT])?;
+                write_bytes(writer, key)?;
+                write_bytes(writer, value)?;
+            }
+            Request::Ttl { key } => {
+                writer.write_all(&[TTL])?;
+                write_bytes(writer, key)?;
+            }
+            Request::ExpireAt { key, at_millis } => {
+                writer.write_all(&[EXPIRE_AT])?;
+                write_bytes(writer, key)?;
+                writer.write_all(&at_millis.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads a length-prefixed byte string.
+pub fn read_bytes(reader: &mut dyn Read) -> error::Result<Vec<u8>> {
+    let mut len_bytes = [0; 4];
+    reader.read_exact(&mut len_bytes)?;
+    let len = u32::from_le_bytes(len_bytes);
+
+    // The length comes from the peer, so don't trust it with an up-front
+    // allocation.
+    let mut bytes = vec![];
+    reader.take(u64::from(len)).read_to_end(&mut bytes)?;
+    if bytes.len() < len as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(bytes)
+}
+
+/// Writes a length-prefixed byte string.
+pub fn write_bytes(writer: &mut dyn Write, bytes: &[u8]) -> error::Result<()> {
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+/// Reads a little-endian `u64`.
+fn read_u64(reader: &mut dyn Read) -> error::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Reads a byte string which may be missing.
+fn read_optional_bytes(reader: &mut dyn Read) -> error::Result<Option<Vec<u8>>> {
+    let mut present = [0; 1];
+    reader.read_exact(&mut present)?;
+    match present[0] {
+        0 => Ok(None),
+        1 => Ok(Some(read_bytes(reader)?)),
+        _ => Err(KvsError::String(String::from("Malformed request"))),
+    }
+}
+
+/// Writes a byte string which may be missing.
+fn write_optional_bytes(writer: &mut dyn Write, bytes: Option<&[u8]>) -> error::Result<()> {
+    match bytes {
+        Some(bytes) => {
+            writer.write_all(&[1])?;
+            write_bytes(writer, bytes)
         }
+        None => Ok(writer.write_all(&[0])?),
     }
 }
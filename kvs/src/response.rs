@@ -1,22 +1,45 @@
-use std::io::BufRead;
+use std::io::{BufRead, Write};
 
 use crate::error;
+use crate::request::{read_bytes, write_bytes};
 use crate::KvsError;
 
-pub fn from_reader(reader: &mut dyn BufRead) -> error::Result<Option<String>> {
-    match reader.lines().next() {
-        Some(resp) => {
-            let resp = resp?;
+const VALUE: u8 = b'+';
+const NO_VALUE: u8 = b'-';
+const ERROR: u8 = b'!';
 
-            if resp.starts_with('!') {
-                Err(KvsError::String(resp))
-            // TODO: Handle `None` types more robustly.
-            } else if resp == "-1" {
-                Ok(None)
-            } else {
-                Ok(Some(resp))
-            }
+/// Reads a response from the server.
+///
+/// A response is a single status byte. A value or error message follows it
+/// as a length-prefixed byte string; a successful response without a value,
+/// including a get of a missing key, has nothing after the status.
+pub fn from_reader(reader: &mut dyn BufRead) -> error::Result<Option<Vec<u8>>> {
+    let mut status = [0; 1];
+    reader
+        .read_exact(&mut status)
+        .map_err(|_| KvsError::String(String::from("Malformed response")))?;
+
+    match status[0] {
+        VALUE => Ok(Some(read_bytes(reader)?)),
+        NO_VALUE => Ok(None),
+        ERROR => Err(KvsError::String(String::from_utf8(read_bytes(reader)?)?)),
+        _ => Err(KvsError::String(String::from("Malformed response"))),
+    }
+}
+
+/// Writes a successful response, with or without a value.
+pub fn to_writer(writer: &mut dyn Write, value: Option<&[u8]>) -> error::Result<()> {
+    match value {
+        Some(value) => {
+            writer.write_all(&[VALUE])?;
+            write_bytes(writer, value)
         }
-        None => Err(KvsError::String(String::from("Malformed response"))),
+        None => Ok(writer.write_all(&[NO_VALUE])?),
     }
 }
+
+/// Writes an error response.
+pub fn error_to_writer(writer: &mut dyn Write, e: &KvsError) -> error::Result<()> {
+    writer.write_all(&[ERROR])?;
+    write_bytes(writer, e.to_string().as_bytes())
+}
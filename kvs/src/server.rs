@@ -1,8 +1,10 @@
 use std::io::{BufReader, BufWriter, Write};
 use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::time::{Duration, UNIX_EPOCH};
 
 use crate::error;
 use crate::request::Request;
+use crate::response;
 use crate::KvsEngine;
 
 /// A key-value server.
@@ -17,7 +19,7 @@ impl<E: KvsEngine> KvsServer<E> {
     }
 
     /// Run the server.
-    pub fn run<A: ToSocketAddrs>(mut self, addr: A) -> error::Result<()> {
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> error::Result<()> {
         let listener = TcpListener::bind(addr)?;
         for stream in listener.incoming() {
             match stream {
@@ -32,28 +34,52 @@ impl<E: KvsEngine> KvsServer<E> {
         Ok(())
     }
 
-    fn serve(&mut self, tcp: TcpStream) -> error::Result<()> {
+    fn serve(&self, tcp: TcpStream) -> error::Result<()> {
         let peer_addr = tcp.peer_addr()?;
         let mut reader = BufReader::new(&tcp);
         let mut writer = BufWriter::new(&tcp);
 
         let req = Request::from_reader(&mut reader)?;
         debug!("Received request from {}: {:?}", peer_addr, req);
-        match req {
-            Request::Get { key } => match self.engine.get(key.clone()) {
-                Ok(Some(value)) => writer.write_all(format!("{}\r\n", value).as_bytes())?,
-                Ok(None) => writer.write_all(b"-1\r\n")?,
-                Err(e) => writer.write_all(format!("!{}\r\n", e).as_bytes())?,
-            },
-            Request::Set { key, value } => match self.engine.set(key, value) {
-                Ok(_) => writer.write_all(b"OK")?,
-                Err(e) => writer.write_all(format!("!{}\r\n", e).as_bytes())?,
-            },
-            Request::Remove { key } => match self.engine.remove(key) {
-                Ok(_) => writer.write_all(b"OK")?,
-                Err(e) => writer.write_all(format!("!{}\r\n", e).as_bytes())?,
-            },
+        let res = match req {
+            Request::Get { key } => self.engine.get_bytes(key.as_slice()).map_err(|e| {
+                error!("Failed to get {}: {}", String::from_utf8_lossy(&key), e);
+                e
+            }),
+            Request::Set { key, value } => self.engine.set_bytes(key, value).map(|_| None),
+            Request::Remove { key } => self.engine.remove_bytes(key).map(|_| None),
+            Request::SetWithTtl {
+                key,
+                value,
+                ttl_millis,
+            } => self
+                .engine
+                .set_with_ttl(key, value, Duration::from_millis(ttl_millis))
+                .map(|_| None),
+            Request::CompareAndSwap { key, expected, new } => self
+                .engine
+                .compare_and_swap_bytes(key, expected, new)
+                .map(|_| None),
+            Request::SetIfAbsent { key, value } => {
+                self.engine.set_if_absent_bytes(key, value).map(|_| None)
+            }
+            Request::SetIfPresent { key, value } => {
+                self.engine.set_if_present_bytes(key, value).map(|_| None)
+            }
+            Request::Ttl { key } => self
+                .engine
+                .ttl(key)
+                .map(|ttl| ttl.map(|ttl| (ttl.as_millis() as u64).to_le_bytes().to_vec())),
+            Request::ExpireAt { key, at_millis } => self
+                .engine
+                .expire_at(key, UNIX_EPOCH + Duration::from_millis(at_millis))
+                .map(|_| None),
+        };
+        match res {
+            Ok(value) => response::to_writer(&mut writer, value.as_deref())?,
+            Err(e) => response::error_to_writer(&mut writer, &e)?,
         }
+        writer.flush()?;
 
         Ok(())
     }
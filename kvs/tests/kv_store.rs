@@ -1,4 +1,14 @@
-use kvs::{KvStore, KvsEngine, Result};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use kvs::{
+    Clock, Codec, CompactionTrigger, CorruptionPolicy, EncryptionKey, KvPair, KvStore,
+    KvStoreOptions, KvsEngine, KvsError, ManualClock, Result, SledKvsEngine, SyncPolicy,
+    WriteBatch,
+};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -6,7 +16,7 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -16,7 +26,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -27,7 +37,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -36,7 +46,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -48,14 +58,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -64,7 +74,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -72,7 +82,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -84,7 +94,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -104,6 +114,7 @@ fn compaction() -> Result<()> {
             let value = format!("{}", iter);
             store.set(key, value)?;
         }
+        store.wait_for_compaction();
 
         let new_size = dir_size();
         if new_size > current_size {
@@ -114,7 +125,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -124,3 +135,1854 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should write hint files on close and fall back to the log when they are
+// missing or corrupt.
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let hint_path = temp_dir.path().join(".kvsdata").join("1.hint");
+    assert!(hint_path.exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    fs::write(&hint_path, b"garbage")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    fs::remove_file(&hint_path)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Writes issued while a background compaction is running must not be lost.
+#[test]
+fn writes_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..200 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("199".to_owned()));
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("199".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A read-only store should serve reads but refuse writes and leave the data
+// directory untouched.
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let file_count = || {
+        fs::read_dir(temp_dir.path().join(".kvsdata"))
+            .unwrap()
+            .count()
+    };
+    let files_before = file_count();
+
+    let options = KvStoreOptions::new().read_only(true);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    match store.set("key2".to_owned(), "value2".to_owned()) {
+        Err(KvsError::ReadOnly) => {}
+        res => panic!("expected a read-only error, got {:?}", res),
+    }
+    drop(store);
+
+    assert_eq!(file_count(), files_before);
+
+    Ok(())
+}
+
+// Only one store at a time should be able to write to a data directory, while
+// read-only stores can open it alongside the writer.
+#[test]
+fn exclusive_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Locked { pid }) => assert_eq!(pid, std::process::id()),
+        res => panic!("expected a locked error, got {:?}", res.err()),
+    }
+
+    let options = KvStoreOptions::new().read_only(true);
+    let reader = KvStore::open_with(temp_dir.path(), options.clone())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(reader.get("key2".to_owned())?, None);
+    let reader = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // The lock is released once the last handle of the writer is dropped.
+    let handle = store.clone();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(handle);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A checkpoint should open as the store was when it was taken, even while
+// writes and compactions carry on.
+#[test]
+fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_file_size(1024)
+        .compaction_trigger(CompactionTrigger::Bytes(4 * 1024));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("removed".to_owned(), "value".to_owned())?;
+    store.remove("removed".to_owned())?;
+
+    // Keys are written in order, so a consistent checkpoint holds every key
+    // up to some point and none after it.
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for key_id in 0..2000 {
+                store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+            }
+            Ok(())
+        })
+    };
+    let checkpoint_dirs: Vec<_> = (0..5)
+        .map(|_| {
+            let checkpoint_dir = TempDir::new().unwrap();
+            store.checkpoint(checkpoint_dir.path()).unwrap();
+            thread::sleep(Duration::from_millis(5));
+            checkpoint_dir
+        })
+        .collect();
+    writer.join().unwrap()?;
+
+    for checkpoint_dir in &checkpoint_dirs {
+        // The manifest lists every file with its length.
+        let data_dir = checkpoint_dir.path().join(".kvsdata");
+        let manifest = fs::read_to_string(data_dir.join("MANIFEST"))?;
+        let mut lines = manifest.lines();
+        assert_eq!(lines.next(), Some("kvs manifest 1"));
+        for line in lines {
+            let fields: Vec<_> = line.split(' ').collect();
+            let len = fs::metadata(data_dir.join(fields[0]))?.len();
+            assert_eq!(fields[1], len.to_string());
+        }
+
+        let checkpoint = KvStore::open_with(checkpoint_dir.path(), options.clone())?;
+        assert_eq!(checkpoint.get("removed".to_owned())?, None);
+        let count = (0..2000)
+            .take_while(|key_id| checkpoint.get(format!("key{}", key_id)).unwrap().is_some())
+            .count();
+        for key_id in count..2000 {
+            assert_eq!(checkpoint.get(format!("key{}", key_id))?, None);
+        }
+    }
+
+    // The checkpoint directory must be empty, and read-only stores cannot
+    // seal a generation.
+    assert!(store.checkpoint(checkpoint_dirs[0].path()).is_err());
+    drop(store);
+    let read_only = KvStore::open_with(temp_dir.path(), options.read_only(true))?;
+    let checkpoint_dir = TempDir::new().unwrap();
+    match read_only.checkpoint(checkpoint_dir.path()) {
+        Err(KvsError::ReadOnly) => {}
+        res => panic!("expected a read-only error, got {:?}", res),
+    }
+
+    Ok(())
+}
+
+// A sled checkpoint should open as the database was when it was taken.
+#[test]
+fn sled_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path().join("db"))?)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+
+    engine.checkpoint(temp_dir.path().join("checkpoint"))?;
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    assert!(engine
+        .checkpoint(temp_dir.path().join("checkpoint"))
+        .is_err());
+
+    let checkpoint = SledKvsEngine::new(reopen_sled(&temp_dir.path().join("checkpoint"))?)?;
+    assert_eq!(
+        checkpoint.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert!(checkpoint.ttl("key2".to_owned())?.is_some());
+    assert_eq!(checkpoint.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// Incremental backups should only copy new files, and restore the store as it
+// was at any increment.
+#[test]
+fn incremental_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let log_dir = temp_dir.path().join(".kvsdata");
+    let options = KvStoreOptions::new().max_file_size(1024);
+    let set_keys = |keys: std::ops::Range<usize>| -> Result<()> {
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        for key_id in keys {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        Ok(())
+    };
+
+    set_keys(0..100)?;
+    let base = kvs::backup(&log_dir, backup_dir.path())?;
+    assert_eq!(base.increment, 0);
+    assert_eq!(base.copied, base.files);
+    set_keys(100..200)?;
+    let increment = kvs::backup(&log_dir, backup_dir.path())?;
+    assert_eq!(increment.increment, 1);
+    assert!(increment.copied < increment.files);
+
+    let restored = |increment: Option<u64>, keys: usize| -> Result<()> {
+        let restore_dir = TempDir::new().expect("unable to create temporary restore directory");
+        kvs::restore(
+            backup_dir.path(),
+            increment,
+            &restore_dir.path().join(".kvsdata"),
+        )?;
+        let store = KvStore::open(restore_dir.path())?;
+        for key_id in 0..200 {
+            let value = Some(format!("value{}", key_id)).filter(|_| key_id < keys);
+            assert_eq!(store.get(format!("key{}", key_id))?, value);
+        }
+        Ok(())
+    };
+    restored(None, 200)?;
+    restored(Some(1), 200)?;
+    restored(Some(0), 100)?;
+    assert!(kvs::restore(backup_dir.path(), Some(2), &temp_dir.path().join("none")).is_err());
+
+    // A running store's active generation is left out of the backup.
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("unsealed".to_owned(), "value".to_owned())?;
+    let online = kvs::backup(&log_dir, backup_dir.path())?;
+    drop(store);
+    assert_eq!(online.increment, 2);
+    restored(None, 200)?;
+    let restore_dir = TempDir::new().expect("unable to create temporary restore directory");
+    kvs::restore(
+        backup_dir.path(),
+        None,
+        &restore_dir.path().join(".kvsdata"),
+    )?;
+    assert_eq!(
+        KvStore::open(restore_dir.path())?.get("unsealed".to_owned())?,
+        None
+    );
+
+    // A damaged copy fails the restore rather than restoring bad data.
+    let copy = fs::read_dir(backup_dir.path().join("0"))?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some("log".as_ref()))
+        .unwrap();
+    let mut bytes = fs::read(&copy)?;
+    *bytes.last_mut().unwrap() ^= 0xff;
+    fs::write(&copy, bytes)?;
+    let restore_dir = TempDir::new().expect("unable to create temporary restore directory");
+    assert!(kvs::restore(backup_dir.path(), Some(0), restore_dir.path()).is_err());
+
+    Ok(())
+}
+
+// The active log file should be rotated once it exceeds the maximum size.
+#[test]
+fn log_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().data_dir("data").max_file_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let log_count = fs::read_dir(temp_dir.path().join("data"))?
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+        .count();
+    assert!(log_count > 1);
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Compaction should split its output into generations no bigger than the
+// maximum file size.
+#[test]
+fn compaction_splits_output() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_dir = temp_dir.path().join(".kvsdata");
+    let options = KvStoreOptions::new()
+        .max_file_size(4096)
+        .compaction_trigger(CompactionTrigger::Bytes(100 * 1024));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    let value = |key_id: usize, round: usize| format!("{:0>100}", key_id * round);
+    for round in 1..=2 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), value(key_id, round))?;
+        }
+    }
+    drop(store);
+
+    let log_sizes: Vec<u64> = fs::read_dir(&log_dir)?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .map(|path| fs::metadata(path).unwrap().len())
+        .collect();
+    // One round of values fills about 32 files; only the live one is kept.
+    assert!(log_sizes.len() < 50, "{} log files left", log_sizes.len());
+    for size in log_sizes {
+        assert!(size < 4096 + 256, "log file of {} bytes", size);
+    }
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id, 2)));
+    }
+
+    Ok(())
+}
+
+// Reads should see the same values whether sealed generations are mapped or
+// not, as the writer rotates and compaction replaces generations.
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .mmap_reads(true)
+        .max_file_size(1024)
+        .compaction_trigger(CompactionTrigger::Bytes(16 * 1024));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    for round in 0..5 {
+        for key_id in 0..100 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, round),
+            )?;
+        }
+        let mut snapshot = store.snapshot();
+        for key_id in 0..100 {
+            let value = format!("value{}-{}", key_id, round);
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+            assert_eq!(
+                snapshot.get(format!("key{}", key_id))?,
+                Some(value.into_bytes())
+            );
+        }
+    }
+    drop(store);
+
+    for options in [options.clone(), options.read_only(true)] {
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}-4", key_id))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// A partially written or corrupt entry at the end of the newest log should be
+// truncated away instead of failing open.
+#[test]
+fn torn_tail_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join(".kvsdata").join("1.log");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let log_len = fs::metadata(&log_path)?.len();
+
+    // Append the first half of another entry.
+    let mut log = fs::read(&log_path)?;
+    let torn = log[..log.len() / 4].to_vec();
+    log.extend_from_slice(&torn);
+    fs::write(&log_path, &log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    assert_eq!(fs::metadata(&log_path)?.len(), log_len);
+
+    // Flip a bit in the last entry of the newest log, and drop its hint file
+    // as a crash would have.
+    let newest_path = temp_dir.path().join(".kvsdata").join("3.log");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let mut log = fs::read(&newest_path)?;
+    *log.last_mut().unwrap() ^= 1;
+    fs::write(&newest_path, &log)?;
+    fs::remove_file(newest_path.with_extension("hint"))?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// A corrupt entry should surface as a typed error from `get` and `open`, and
+// the corruption policy should decide how `open` deals with it.
+#[test]
+fn corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join(".kvsdata").join("1.log");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Flip a bit in the value of the first entry.
+    let mut log = fs::read(&log_path)?;
+    let value_pos = log
+        .windows(6)
+        .position(|window| window == b"value1")
+        .unwrap();
+    log[value_pos] ^= 1;
+    fs::write(&log_path, &log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    match store.get("key1".to_owned()) {
+        Err(KvsError::Corruption { gen: 1, .. }) => {}
+        res => panic!("expected a corruption error, got {:?}", res),
+    }
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // Without the hint file the log has to be replayed.
+    fs::remove_file(log_path.with_extension("hint"))?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { gen: 1, .. }) => {}
+        Err(e) => panic!("expected a corruption error, got {:?}", e),
+        Ok(_) => panic!("expected a corruption error"),
+    }
+
+    let options = KvStoreOptions::new()
+        .corruption_policy(CorruptionPolicy::Skip)
+        .read_only(true);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    let options = KvStoreOptions::new().corruption_policy(CorruptionPolicy::Quarantine);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(log_path.with_extension("log.corrupt").exists());
+    assert!(!log_path.exists());
+
+    Ok(())
+}
+
+// An expiring entry too short to hold its expiry time should be reported as
+// corrupt, even though its checksum matches
+#[test]
+fn truncated_expiring_entry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join(".kvsdata").join("1.log");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    fs::remove_file(log_path.with_extension("hint"))?;
+
+    // An expiring put whose value holds 4 bytes instead of an 8-byte expiry.
+    let mut bytes = vec![3, 0];
+    bytes.extend_from_slice(&4u32.to_le_bytes());
+    bytes.extend_from_slice(&4u32.to_le_bytes());
+    bytes.extend_from_slice(b"key0abcd");
+    let mut record = crc32fast::hash(&bytes).to_le_bytes().to_vec();
+    record.extend(bytes);
+
+    // Insert it before the first entry, whose 14-byte prefix precedes its key.
+    let mut log = fs::read(&log_path)?;
+    let entry_pos = log.windows(4).position(|window| window == b"key1").unwrap() - 14;
+    log.splice(entry_pos..entry_pos, record);
+    fs::write(&log_path, &log)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { gen: 1, .. }) => {}
+        Err(e) => panic!("expected a corruption error, got {:?}", e),
+        Ok(_) => panic!("expected a corruption error"),
+    }
+
+    Ok(())
+}
+
+// Verifying a data directory should count live and dead entries, tell torn
+// writes from corruption and report every corrupt range without modifying
+// the logs
+#[test]
+fn verify() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_dir = temp_dir.path().join(".kvsdata");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    store.set("key4".to_owned(), "value5".to_owned())?;
+    drop(store);
+
+    let report = kvs::verify(&log_dir, None)?;
+    assert!(report.is_ok());
+    assert_eq!(report.generations, 2);
+    assert_eq!(report.entries, 6);
+    assert_eq!((report.live, report.dead()), (3, 3));
+    assert_eq!(report.torn, None);
+
+    // Cut the end off the newest entry, as a crash in the middle of writing
+    // it would.
+    let log = fs::OpenOptions::new()
+        .write(true)
+        .open(log_dir.join("2.log"))?;
+    let log_len = log.metadata()?.len() - 1;
+    log.set_len(log_len)?;
+
+    let report = kvs::verify(&log_dir, None)?;
+    assert!(report.is_ok());
+    assert_eq!((report.live, report.dead()), (2, 3));
+    let torn = report.torn.expect("expected a torn write");
+    assert_eq!((torn.gen, torn.range.end), (2, log_len));
+
+    // Flip a bit in the value of the first entry.
+    let log_path = log_dir.join("1.log");
+    let mut log = fs::read(&log_path)?;
+    let value_pos = log
+        .windows(6)
+        .position(|window| window == b"value1")
+        .unwrap();
+    log[value_pos] ^= 1;
+    fs::write(&log_path, &log)?;
+
+    let report = kvs::verify(&log_dir, None)?;
+    assert!(!report.is_ok());
+    assert_eq!(report.corrupt.len(), 1);
+    let corrupt = &report.corrupt[0];
+    assert_eq!(corrupt.gen, 1);
+    assert!(corrupt.range.contains(&(value_pos as u64)));
+    assert_eq!(report.entries, 4);
+    assert!(report.torn.is_some());
+
+    // Verifying leaves the torn write and the corrupt entry in place.
+    assert_eq!(fs::read(&log_path)?, log);
+    assert_eq!(fs::metadata(log_dir.join("2.log"))?.len(), log_len);
+
+    Ok(())
+}
+
+// Empty values should round-trip rather than being replayed as removals
+#[test]
+fn empty_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+    drop(store);
+
+    // Without the hint file the log has to be replayed.
+    fs::remove_file(temp_dir.path().join(".kvsdata").join("1.hint"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+
+    Ok(())
+}
+
+// Logs written in older formats should still be readable, and should be
+// upgraded to the current format on open
+#[test]
+fn old_log_formats() -> Result<()> {
+    // Entries of version 1 logs carry a kind; legacy entries do not.
+    fn old_entry(kind: Option<u8>, key: &str, value: &str) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend(kind);
+        bytes.extend_from_slice(&(key.len() as u32).to_ne_bytes());
+        bytes.extend_from_slice(&(value.len() as u32).to_ne_bytes());
+        bytes.extend_from_slice(key.as_bytes());
+        bytes.extend_from_slice(value.as_bytes());
+
+        let mut crc_hasher = crc32fast::Hasher::new();
+        crc_hasher.update(&bytes);
+        let mut entry = crc_hasher.finalize().to_be_bytes().to_vec();
+        entry.extend_from_slice(&bytes);
+        entry
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_dir = temp_dir.path().join(".kvsdata");
+    fs::create_dir(&log_dir)?;
+
+    let mut log = vec![];
+    log.extend(old_entry(None, "key1", "value1"));
+    log.extend(old_entry(None, "key2", "value2"));
+    log.extend(old_entry(None, "key2", ""));
+    fs::write(log_dir.join("1.log"), log)?;
+
+    let mut log = b"KVS\0\x01".to_vec();
+    log.extend(old_entry(Some(0), "key3", ""));
+    log.extend(old_entry(Some(0), "key4", "value4"));
+    log.extend(old_entry(Some(1), "key4", ""));
+    fs::write(log_dir.join("2.log"), log)?;
+
+    // Version 2 entries are little-endian but have no codec.
+    let mut log = b"KVS\0\x02".to_vec();
+    log.extend_from_slice(&0u64.to_le_bytes());
+    log.extend_from_slice(&3u64.to_le_bytes());
+    let mut bytes = vec![0];
+    bytes.extend_from_slice(&4u32.to_le_bytes());
+    bytes.extend_from_slice(&6u32.to_le_bytes());
+    bytes.extend_from_slice(b"key5value5");
+    log.extend_from_slice(&crc32fast::hash(&bytes).to_le_bytes());
+    log.extend(bytes);
+    fs::write(log_dir.join("3.log"), log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, None);
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+
+    // The upgrade rewrites every entry at a new length, which later writes
+    // must account for.
+    store.wait_for_compaction();
+    let live = [("key1", "value1"), ("key3", ""), ("key5", "value5")];
+    for (key, _) in &live {
+        store.remove(key.to_string())?;
+    }
+    for (key, value) in &live {
+        store.set(key.to_string(), value.to_string())?;
+    }
+    drop(store);
+
+    assert!(!log_dir.join("1.log").exists());
+    assert!(!log_dir.join("2.log").exists());
+    assert!(!log_dir.join("3.log").exists());
+
+    // The upgraded log starts with the magic number, version and generation.
+    let log = fs::read(log_dir.join("5.log"))?;
+    assert_eq!(&log[..5], b"KVS\0\x04");
+    assert_eq!(&log[13..21], &5u64.to_le_bytes());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, None);
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+// A batch in a log written in an older format should be replayed at the
+// positions that format lays its entries out at
+#[test]
+fn old_log_format_batch() -> Result<()> {
+    // Version 2 records are little-endian, with a checksum, kind and sizes.
+    fn v2_record(kind: u8, key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![kind];
+        bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(key);
+        bytes.extend_from_slice(value);
+
+        let mut record = crc32fast::hash(&bytes).to_le_bytes().to_vec();
+        record.extend(bytes);
+        record
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_dir = temp_dir.path().join(".kvsdata");
+    fs::create_dir(&log_dir)?;
+
+    let mut batch = v2_record(0, b"a", b"value1");
+    batch.extend(v2_record(0, b"b", b"value2"));
+    let mut log = b"KVS\0\x02".to_vec();
+    log.extend_from_slice(&0u64.to_le_bytes());
+    log.extend_from_slice(&1u64.to_le_bytes());
+    log.extend(v2_record(2, b"", &batch));
+    // There is no hint file, as after a crash, so the batch is replayed.
+    fs::write(log_dir.join("1.log"), log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a")?, Some("value1".to_owned()));
+    assert_eq!(store.get("b")?, Some("value2".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a")?, Some("value1".to_owned()));
+    assert_eq!(store.get("b")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Logs written in a newer format should be refused
+#[test]
+fn unsupported_log_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_dir = temp_dir.path().join(".kvsdata");
+    fs::create_dir(&log_dir)?;
+    fs::write(log_dir.join("1.log"), b"KVS\0\xff")?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedVersion { version: 0xff }) => {}
+        Err(e) => panic!("expected an unsupported version error, got {:?}", e),
+        Ok(_) => panic!("expected an unsupported version error"),
+    }
+
+    Ok(())
+}
+
+// Keys and values should be able to hold arbitrary bytes
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let key = b"\x00\xff\xfe".to_vec();
+    store.set_bytes(key.clone(), &b"\xc3\x28"[..])?;
+    store.set_bytes(&b"key2"[..], &b"\xff"[..])?;
+    store.set_bytes(&b"key3"[..], &b"value3"[..])?;
+    store.remove_bytes(&b"key3"[..])?;
+    assert_eq!(store.get_bytes(key.clone())?, Some(b"\xc3\x28".to_vec()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(key.clone())?, Some(b"\xc3\x28".to_vec()));
+    assert_eq!(store.get_bytes(&b"key3"[..])?, None);
+    match store.get("key2") {
+        Err(KvsError::FromUtf8(_)) => {}
+        res => panic!("expected a UTF-8 error, got {:?}", res),
+    }
+    drop(store);
+
+    // Without the hint files the logs have to be replayed.
+    for entry in fs::read_dir(temp_dir.path().join(".kvsdata"))? {
+        let path = entry?.path();
+        if path.extension() == Some("hint".as_ref()) {
+            fs::remove_file(path)?;
+        }
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(key)?, Some(b"\xc3\x28".to_vec()));
+    assert_eq!(store.get_bytes(&b"key2"[..])?, Some(b"\xff".to_vec()));
+    assert_eq!(store.get_bytes(&b"key3"[..])?, None);
+
+    Ok(())
+}
+
+fn check_scans(engine: &impl KvsEngine) -> Result<()> {
+    for key in &["a", "ab", "abc", "b", "b\u{ff}", "c"] {
+        engine.set(*key, key.to_uppercase())?;
+    }
+    engine.set_bytes(&b"b\xff"[..], &b"B\xff"[..])?;
+
+    let keys = |scan: Vec<Result<KvPair>>| -> Result<Vec<Vec<u8>>> {
+        scan.into_iter().map(|pair| Ok(pair?.0)).collect()
+    };
+
+    let scan = engine.scan(b"ab".to_vec()..b"b\xff".to_vec())?.collect();
+    assert_eq!(
+        keys(scan)?,
+        vec![
+            b"ab".to_vec(),
+            b"abc".to_vec(),
+            b"b".to_vec(),
+            "b\u{ff}".into()
+        ]
+    );
+
+    let scan = engine.scan(..)?.rev().collect();
+    assert_eq!(
+        keys(scan)?,
+        vec![
+            b"c".to_vec(),
+            b"b\xff".to_vec(),
+            "b\u{ff}".into(),
+            b"b".to_vec(),
+            b"abc".to_vec(),
+            b"ab".to_vec(),
+            b"a".to_vec(),
+        ]
+    );
+
+    let scan: Vec<_> = engine.scan_prefix(&b"ab"[..])?.collect::<Result<_>>()?;
+    assert_eq!(
+        scan,
+        vec![
+            (b"ab".to_vec(), b"AB".to_vec()),
+            (b"abc".to_vec(), b"ABC".to_vec()),
+        ]
+    );
+
+    let scan = engine.scan_prefix(&b"b"[..])?.rev().collect();
+    assert_eq!(
+        keys(scan)?,
+        vec![b"b\xff".to_vec(), "b\u{ff}".into(), b"b".to_vec()]
+    );
+
+    assert_eq!(engine.scan_prefix(&b"d"[..])?.count(), 0);
+    assert_eq!(engine.scan(b"c".to_vec()..b"a".to_vec())?.count(), 0);
+
+    Ok(())
+}
+
+// Scans should return keys in order, forwards and backwards
+#[test]
+fn scans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scans(&KvStore::open(temp_dir.path())?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scans(&SledKvsEngine::new(sled::open(temp_dir.path())?)?)?;
+
+    Ok(())
+}
+
+// A scan should still return the values it started with after a compaction
+// moves and deletes them
+#[test]
+fn scan_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_trigger(CompactionTrigger::Bytes(64 * 1024));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{:03}", key_id), "old")?;
+    }
+    let mut scan = store.scan(..)?;
+    assert_eq!(
+        scan.next().transpose()?,
+        Some((b"key000".to_vec(), b"old".to_vec()))
+    );
+
+    let value = "new".repeat(1000);
+    for _ in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{:03}", key_id), value.as_str())?;
+        }
+    }
+    // Waits for the compaction to finish and delete the logs.
+    drop(store);
+    assert!(!temp_dir.path().join(".kvsdata").join("1.log").exists());
+
+    let rest: Vec<KvPair> = scan.collect::<Result<_>>()?;
+    assert_eq!(rest.len(), 99);
+    for (key_id, (key, value)) in (1..100).zip(rest) {
+        assert_eq!(key, format!("key{:03}", key_id).into_bytes());
+        assert_eq!(value, b"old");
+    }
+
+    Ok(())
+}
+
+fn check_write_batch(engine: &impl KvsEngine) -> Result<()> {
+    engine.set("key1", "value1")?;
+    engine.set("key2", "value2")?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .put("key1", "value3")
+        .delete("key2")
+        .delete("key3")
+        .put("key4", "value4")
+        .put("key4", "value5");
+    engine.write_batch(batch)?;
+
+    assert_eq!(engine.get("key1")?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key2")?, None);
+    assert_eq!(engine.get("key3")?, None);
+    assert_eq!(engine.get("key4")?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+// A batch should apply all of its operations, and after a crash either all
+// or none of them
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_write_batch(&SledKvsEngine::new(sled::open(temp_dir.path())?)?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_dir = temp_dir.path().join(".kvsdata");
+    let store = KvStore::open(temp_dir.path())?;
+    check_write_batch(&store)?;
+    drop(store);
+
+    // Without the hint file the batch has to be replayed.
+    fs::remove_file(log_dir.join("1.hint"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key4")?, Some("value5".to_owned()));
+
+    // Cut the end off a batch, as a crash in the middle of writing it would.
+    let mut batch = WriteBatch::new();
+    batch.put("key1", "value6").put("key5", "value7");
+    store.write_batch(batch)?;
+    drop(store);
+    let log = fs::OpenOptions::new()
+        .write(true)
+        .open(log_dir.join("2.log"))?;
+    log.set_len(log.metadata()?.len() - 1)?;
+    fs::remove_file(log_dir.join("2.hint"))?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
+    assert_eq!(store.get("key5")?, None);
+
+    Ok(())
+}
+
+// A transaction should commit its writes unless a key it read has changed
+#[test]
+fn transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+
+    let mut txn = store.begin();
+    assert_eq!(txn.get("key1")?, Some(b"value1".to_vec()));
+    assert_eq!(txn.get("key3")?, None);
+    txn.set("key1", "value3");
+    txn.remove("key2");
+    txn.set("key3", "value4");
+    assert_eq!(txn.get("key1")?, Some(b"value3".to_vec()));
+    assert_eq!(txn.get("key2")?, None);
+    // Nothing is visible before the commit.
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    txn.commit()?;
+
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key3")?, Some("value4".to_owned()));
+
+    // A key read by the transaction is overwritten before it commits.
+    let mut txn = store.begin();
+    txn.get("key1")?;
+    txn.set("key4", "value5");
+    store.set("key1", "value3")?;
+    match txn.commit() {
+        Err(KvsError::Conflict) => {}
+        res => panic!("expected a conflict, got {:?}", res),
+    }
+    assert_eq!(store.get("key4")?, None);
+
+    // A key the transaction found missing is created before it commits.
+    let mut txn = store.begin();
+    txn.get("key5")?;
+    txn.set("key4", "value5");
+    store.set("key5", "value6")?;
+    match txn.commit() {
+        Err(KvsError::Conflict) => {}
+        res => panic!("expected a conflict, got {:?}", res),
+    }
+
+    // Writes to keys the transaction did not read do not conflict.
+    let mut txn = store.begin();
+    txn.get("key1")?;
+    txn.set("key4", "value5");
+    store.set("key5", "value7")?;
+    txn.commit()?;
+    assert_eq!(store.get("key4")?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+// Compaction moving a key a transaction read should not make it conflict
+#[test]
+fn transaction_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_trigger(CompactionTrigger::Bytes(64 * 1024));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1", "value1")?;
+
+    let mut txn = store.begin();
+    txn.get("key1")?;
+    txn.set("key1", "value2");
+
+    let value = "x".repeat(1000);
+    for _ in 0..100 {
+        store.set("key2", value.as_str())?;
+    }
+    store.wait_for_compaction();
+    let log_path = temp_dir.path().join(".kvsdata").join("1.log");
+    assert!(!log_path.exists());
+
+    txn.commit()?;
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A snapshot should keep reading the values it was taken with, even after a
+// compaction
+#[test]
+fn snapshots() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_dir = temp_dir.path().join(".kvsdata");
+    let options = KvStoreOptions::new().compaction_trigger(CompactionTrigger::Bytes(64 * 1024));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+
+    let mut snapshot = store.snapshot();
+    store.set("key1", "value3")?;
+    store.remove("key2")?;
+    store.set("key3", "value4")?;
+    assert!(store.snapshot().seq() > snapshot.seq());
+
+    assert_eq!(snapshot.get("key1")?, Some(b"value1".to_vec()));
+    assert_eq!(snapshot.get("key2")?, Some(b"value2".to_vec()));
+    assert_eq!(snapshot.get("key3")?, None);
+
+    // Compact away the generation the snapshot refers to.
+    let value = "x".repeat(1000);
+    for _ in 0..100 {
+        store.set("key4", value.as_str())?;
+    }
+    store.wait_for_compaction();
+    let stale_path = log_dir.join("1.log.stale");
+    assert!(stale_path.exists());
+    assert!(!log_dir.join("1.log").exists());
+
+    assert_eq!(snapshot.get("key1")?, Some(b"value1".to_vec()));
+    let scan: Vec<KvPair> = snapshot.scan(..)?.collect::<Result<_>>()?;
+    assert_eq!(
+        scan,
+        vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec()),
+        ]
+    );
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
+
+    drop(snapshot);
+    assert!(!stale_path.exists());
+
+    // A stale generation left behind by a crash is removed on open.
+    let snapshot = store.snapshot();
+    for _ in 0..100 {
+        store.set("key4", value.as_str())?;
+    }
+    drop(store);
+    std::mem::forget(snapshot);
+    assert!(fs::read_dir(&log_dir)?
+        .any(|entry| entry.unwrap().path().extension() == Some("stale".as_ref())));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!fs::read_dir(&log_dir)?
+        .any(|entry| entry.unwrap().path().extension() == Some("stale".as_ref())));
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+
+    Ok(())
+}
+
+fn check_ttl(engine: &impl KvsEngine, clock: &ManualClock) -> Result<()> {
+    engine.set_with_ttl("session", "abc", Duration::from_secs(30))?;
+    engine.set("persistent", "def")?;
+    assert_eq!(engine.ttl("session")?, Some(Duration::from_secs(30)));
+    assert_eq!(engine.ttl("persistent")?, None);
+
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(engine.get("session")?, Some("abc".to_owned()));
+    assert_eq!(engine.ttl("session")?, Some(Duration::from_secs(20)));
+
+    clock.advance(Duration::from_secs(20));
+    assert_eq!(engine.get("session")?, None);
+    assert!(matches!(engine.ttl("session"), Err(KvsError::KeyNotFound)));
+    assert!(matches!(
+        engine.remove("session"),
+        Err(KvsError::KeyNotFound)
+    ));
+    let keys: Vec<Vec<u8>> = engine.scan(..)?.map(|pair| pair.unwrap().0).collect();
+    assert_eq!(keys, vec![b"persistent".to_vec()]);
+
+    // Setting a key without a time to live makes it persistent again.
+    engine.set_with_ttl("session", "ghi", Duration::from_secs(30))?;
+    engine.set("session", "jkl")?;
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(engine.get("session")?, Some("jkl".to_owned()));
+
+    // So does a time to live too long to store.
+    engine.set_with_ttl("forever", "mno", Duration::MAX)?;
+    assert_eq!(engine.ttl("forever")?, None);
+    assert_eq!(engine.get("forever")?, Some("mno".to_owned()));
+
+    engine.expire_at("persistent", clock.now() + Duration::from_secs(5))?;
+    assert_eq!(engine.ttl("persistent")?, Some(Duration::from_secs(5)));
+    assert_eq!(engine.get("persistent")?, Some("def".to_owned()));
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(engine.get("persistent")?, None);
+    assert!(matches!(
+        engine.expire_at("missing", clock.now()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn ttl() -> Result<()> {
+    let clock = Arc::new(ManualClock::new(
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+    ));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .clock(clock.clone())
+        .sweep_interval(None);
+    check_ttl(&KvStore::open_with(temp_dir.path(), options)?, &clock)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(temp_dir.path())?;
+    check_ttl(&SledKvsEngine::with_clock(db, clock.clone())?, &clock)?;
+
+    Ok(())
+}
+
+// Values written before keys could expire are stored untagged, and should
+// read back unchanged, however they start.
+#[test]
+fn sled_untagged_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(temp_dir.path())?;
+    db.insert(b"key1", b"value1".to_vec())?;
+    db.insert(b"key2", vec![0, 1, 2])?;
+    db.insert(b"key3", vec![1; 12])?;
+
+    // The values are only tagged the first time.
+    for _ in 0..2 {
+        let engine = SledKvsEngine::new(db.clone())?;
+        assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(engine.get_bytes("key2")?, Some(vec![0, 1, 2]));
+        assert_eq!(engine.get_bytes("key3")?, Some(vec![1; 12]));
+        assert_eq!(engine.ttl("key3")?, None);
+        engine.set("key4".to_owned(), "value4".to_owned())?;
+        assert_eq!(engine.scan(..)?.count(), 4);
+    }
+
+    Ok(())
+}
+
+// Untagged values are tagged a chunk at a time, and tagging should pick up
+// where it left off if it was interrupted.
+#[test]
+fn sled_tagging_resumes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(temp_dir.path())?;
+    // Tagging stopped after `key0000`, leaving the rest untagged.
+    db.insert(b"key0000", b"\x00value0000".to_vec())?;
+    for key_id in 1..2500 {
+        db.insert(
+            format!("key{:04}", key_id),
+            format!("value{:04}", key_id).as_bytes(),
+        )?;
+    }
+    db.open_tree("kvs_meta")?.insert("tag_cursor", "key0000")?;
+
+    let engine = SledKvsEngine::new(db)?;
+    for key_id in 0..2500 {
+        assert_eq!(
+            engine.get(format!("key{:04}", key_id))?,
+            Some(format!("value{:04}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Expiry times should survive reopening, whether from hint files or the log
+#[test]
+fn ttl_after_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_dir = temp_dir.path().join(".kvsdata");
+    let clock = Arc::new(ManualClock::new(
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+    ));
+    let options = KvStoreOptions::new()
+        .clock(clock.clone())
+        .sweep_interval(None);
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set_with_ttl("key1", "value1", Duration::from_secs(10))?;
+    store.set_with_ttl("key2", "value2", Duration::from_secs(30))?;
+    store.set("key3", "")?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    assert_eq!(store.ttl("key1")?, Some(Duration::from_secs(10)));
+    assert_eq!(store.get("key3")?, Some("".to_owned()));
+    clock.advance(Duration::from_secs(20));
+    drop(store);
+
+    for hints in &[true, false] {
+        if !hints {
+            fs::remove_file(log_dir.join("1.hint"))?;
+        }
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        assert_eq!(store.get("key1")?, None);
+        assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+        assert_eq!(store.ttl("key2")?, Some(Duration::from_secs(10)));
+    }
+
+    Ok(())
+}
+
+// Compaction should drop expired entries from the log
+#[test]
+fn compaction_drops_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::new(
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+    ));
+    let options = KvStoreOptions::new()
+        .compaction_trigger(CompactionTrigger::Bytes(64 * 1024))
+        .clock(clock.clone())
+        .sweep_interval(None);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    let value = "x".repeat(1000);
+    for key_id in 0..50 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            value.as_str(),
+            Duration::from_secs(10),
+        )?;
+    }
+    store.set("persistent", "value")?;
+    clock.advance(Duration::from_secs(10));
+
+    // Overwrite a persistent key until compaction runs, without reading any
+    // of the expired ones.
+    for _ in 0..100 {
+        store.set("filler", value.as_str())?;
+    }
+    drop(store);
+
+    let dir_size = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok()?.metadata().ok())
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+            .sum::<u64>()
+    };
+    assert!(dir_size() < 50 * 1000, "expired entries were not compacted");
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..50 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    assert_eq!(store.get("persistent")?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// The sweeper should drop expired keys which are never read again, so that
+// they count towards compaction
+#[test]
+fn sweeper() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_dir = temp_dir.path().join(".kvsdata");
+    let clock = Arc::new(ManualClock::new(
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+    ));
+    let options = KvStoreOptions::new()
+        .compaction_trigger(CompactionTrigger::Bytes(4000))
+        .clock(clock.clone())
+        .sweep_interval(Some(Duration::from_millis(10)));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    store.set_with_ttl("key1", "x".repeat(5000), Duration::from_secs(10))?;
+    clock.advance(Duration::from_secs(10));
+    thread::sleep(Duration::from_millis(100));
+
+    // The next write notices the swept key and compacts it away.
+    store.set("key2", "value2")?;
+    store.wait_for_compaction();
+    assert!(!log_dir.join("1.log").exists());
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// sled should also drop expired keys which are never read again
+#[test]
+fn sled_sweeper() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::new(
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+    ));
+    let db = sled::open(temp_dir.path())?;
+    let engine = SledKvsEngine::with_clock(db.clone(), clock.clone())?
+        .sweep_interval(Some(Duration::from_millis(10)));
+
+    engine.set_with_ttl("key1", "value1", Duration::from_secs(10))?;
+    engine.set("key2", "value2")?;
+    clock.advance(Duration::from_secs(10));
+    thread::sleep(Duration::from_millis(100));
+
+    assert!(!db.contains_key(b"key1")?);
+    assert!(db.contains_key(b"key2")?);
+
+    Ok(())
+}
+
+fn check_conditional_writes(engine: &impl KvsEngine) -> Result<()> {
+    engine.compare_and_swap("key1", None, Some("value1".to_owned()))?;
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+
+    match engine.compare_and_swap("key1", Some("other".to_owned()), Some("value2".to_owned())) {
+        Err(KvsError::CompareAndSwap { current }) => {
+            assert_eq!(current, Some(b"value1".to_vec()))
+        }
+        res => panic!("unexpected result {:?}", res),
+    }
+    assert!(matches!(
+        engine.compare_and_swap("key1", None, None),
+        Err(KvsError::CompareAndSwap { .. })
+    ));
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+
+    engine.compare_and_swap("key1", Some("value1".to_owned()), Some("value2".to_owned()))?;
+    assert_eq!(engine.get("key1")?, Some("value2".to_owned()));
+    engine.compare_and_swap("key1", Some("value2".to_owned()), None)?;
+    assert_eq!(engine.get("key1")?, None);
+    engine.compare_and_swap("key1", None, None)?;
+
+    engine.set_if_absent("key2", "value1")?;
+    assert!(matches!(
+        engine.set_if_absent("key2", "value2"),
+        Err(KvsError::CompareAndSwap { .. })
+    ));
+    assert_eq!(engine.get("key2")?, Some("value1".to_owned()));
+
+    engine.set_if_present("key2", "value3")?;
+    assert_eq!(engine.get("key2")?, Some("value3".to_owned()));
+    assert!(matches!(
+        engine.set_if_present("key3", "value1"),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(engine.get("key3")?, None);
+
+    Ok(())
+}
+
+#[test]
+fn conditional_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_conditional_writes(&KvStore::open(temp_dir.path())?)?;
+    // The swapped values should survive a restart.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value3".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_conditional_writes(&SledKvsEngine::new(sled::open(temp_dir.path())?)?)?;
+
+    Ok(())
+}
+
+// An expired key should count as missing to a conditional write
+#[test]
+fn conditional_writes_after_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::new(
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+    ));
+    let options = KvStoreOptions::new()
+        .clock(clock.clone())
+        .sweep_interval(None);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    store.set_with_ttl("key1", "value1", Duration::from_secs(10))?;
+    clock.advance(Duration::from_secs(10));
+    assert!(matches!(
+        store.set_if_present("key1", "value2"),
+        Err(KvsError::KeyNotFound)
+    ));
+    store.set_if_absent("key1", "value3")?;
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
+    assert_eq!(store.ttl("key1")?, None);
+
+    Ok(())
+}
+
+fn check_concurrent_access(engine: &impl KvsEngine) -> Result<()> {
+    engine.set("counter", "0")?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for key_id in 0..100 {
+                    let key = format!("key{}-{}", thread_id, key_id);
+                    engine.set(key.as_str(), format!("value{}", key_id))?;
+                    assert_eq!(engine.get(key.as_str())?, Some(format!("value{}", key_id)));
+
+                    // Increment the shared counter, retrying lost races.
+                    loop {
+                        let counter = engine.get("counter")?.unwrap();
+                        let next = (counter.parse::<u64>().unwrap() + 1).to_string();
+                        match engine.compare_and_swap("counter", Some(counter), Some(next)) {
+                            Err(KvsError::CompareAndSwap { .. }) => continue,
+                            res => break res?,
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    assert_eq!(engine.get("counter")?, Some("800".to_owned()));
+    for thread_id in 0..8 {
+        for key_id in 0..100 {
+            let key = format!("key{}-{}", thread_id, key_id);
+            assert_eq!(engine.get(key)?, Some(format!("value{}", key_id)));
+        }
+    }
+
+    Ok(())
+}
+
+// Clones of an engine should be usable from many threads at once
+#[test]
+fn concurrent_access() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_trigger(CompactionTrigger::Bytes(16 * 1024));
+    check_concurrent_access(&KvStore::open_with(temp_dir.path(), options.clone())?)?;
+    // Every write should survive the last handle being dropped.
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("counter")?, Some("800".to_owned()));
+    assert_eq!(store.get("key7-99")?, Some("value99".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_concurrent_access(&SledKvsEngine::new(sled::open(temp_dir.path())?)?)?;
+
+    Ok(())
+}
+
+fn check_sync_policy(engine: &impl KvsEngine) -> Result<()> {
+    for key_id in 0..100 {
+        engine.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    engine.remove("key0")?;
+    let mut batch = WriteBatch::new();
+    batch.put("key1", "batched");
+    engine.write_batch(batch)?;
+    // Gives `SyncPolicy::EveryMillis` a chance to sync in the background.
+    thread::sleep(Duration::from_millis(30));
+    Ok(())
+}
+
+// sled releases its lock on a database from background threads once the last
+// handle is dropped, so reopening it straight away may find it still locked.
+fn reopen_sled(path: &Path) -> Result<sled::Db> {
+    for _ in 0..100 {
+        if let Ok(db) = sled::open(path) {
+            return Ok(db);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(sled::open(path)?)
+}
+
+// Every sync policy should keep writes across reopening the engine
+#[test]
+fn sync_policies() -> Result<()> {
+    assert_eq!("always".parse::<SyncPolicy>()?, SyncPolicy::Always);
+    assert_eq!("never".parse::<SyncPolicy>()?, SyncPolicy::Never);
+    assert_eq!(
+        "every-millis:100".parse::<SyncPolicy>()?,
+        SyncPolicy::EveryMillis(100)
+    );
+    assert_eq!(
+        "every-bytes:4096".parse::<SyncPolicy>()?,
+        SyncPolicy::EveryBytes(4096)
+    );
+    assert!("every-millis".parse::<SyncPolicy>().is_err());
+    assert!("every-bytes:lots".parse::<SyncPolicy>().is_err());
+    assert!("sometimes:1".parse::<SyncPolicy>().is_err());
+
+    let policies = [
+        SyncPolicy::Always,
+        SyncPolicy::EveryMillis(10),
+        SyncPolicy::EveryBytes(256),
+        SyncPolicy::Never,
+    ];
+    for &policy in &policies {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().sync_policy(policy);
+        check_sync_policy(&KvStore::open_with(temp_dir.path(), options.clone())?)?;
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.get("key0")?, None);
+        assert_eq!(store.get("key1")?, Some("batched".to_owned()));
+        assert_eq!(store.get("key99")?, Some("value99".to_owned()));
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = SledKvsEngine::new(sled::open(temp_dir.path())?)?.sync_policy(policy);
+        check_sync_policy(&engine)?;
+        drop(engine);
+        let engine = SledKvsEngine::new(reopen_sled(temp_dir.path())?)?;
+        assert_eq!(engine.get("key0")?, None);
+        assert_eq!(engine.get("key1")?, Some("batched".to_owned()));
+        assert_eq!(engine.get("key99")?, Some("value99".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Concurrent durable writes should be committed in groups, each with its own
+// result
+#[test]
+fn group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync_policy(SyncPolicy::Always);
+    check_concurrent_access(&KvStore::open_with(temp_dir.path(), options.clone())?)?;
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("counter")?, Some("800".to_owned()));
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for key_id in 0..50 {
+                    let key = format!("key{}-{}", thread_id, key_id);
+                    store.remove(key.as_str())?;
+                    // A failed write in a group leaves the others alone.
+                    assert!(matches!(
+                        store.remove(key.as_str()),
+                        Err(KvsError::KeyNotFound)
+                    ));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key3-49")?, None);
+    assert_eq!(store.get("key3-50")?, Some("value50".to_owned()));
+
+    Ok(())
+}
+
+fn log_size(log_dir: &std::path::Path) -> u64 {
+    WalkDir::new(log_dir)
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum()
+}
+
+// Values should be compressed in the log and read back transparently
+#[test]
+fn compression() -> Result<()> {
+    let json = |i: usize| {
+        format!(
+            r#"{{"id": {}, "name": "user{}", "tags": ["alpha", "beta", "gamma"], "active": true}}"#,
+            i, i
+        )
+        .repeat(8)
+    };
+
+    let mut sizes = vec![];
+    for &codec in &[Codec::None, Codec::Snappy, Codec::Deflate] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let log_dir = temp_dir.path().join(".kvsdata");
+        let options = KvStoreOptions::new()
+            .compression(codec)
+            .compression_threshold(64);
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), json(i))?;
+        }
+        // Values below the threshold stay raw.
+        store.set("small", "tiny")?;
+        store.set_with_ttl("expiring", json(100), Duration::from_secs(3600))?;
+        let mut batch = WriteBatch::new();
+        batch.put("batched", json(101));
+        store.write_batch(batch)?;
+        assert_eq!(store.get("key7")?, Some(json(7)));
+        drop(store);
+        sizes.push(log_size(&log_dir));
+
+        // Replay the log itself rather than its hints.
+        for entry in fs::read_dir(&log_dir)? {
+            let path = entry?.path();
+            if path.extension() == Some("hint".as_ref()) {
+                fs::remove_file(path)?;
+            }
+        }
+        // Reopening with another codec reads both, including once they have
+        // been compacted together.
+        let options = options
+            .compression(Codec::Snappy)
+            .compaction_trigger(CompactionTrigger::Bytes(1));
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        assert_eq!(store.get("key42")?, Some(json(42)));
+        assert_eq!(store.get("small")?, Some("tiny".to_owned()));
+        assert_eq!(store.get("expiring")?, Some(json(100)));
+        assert_eq!(store.get("batched")?, Some(json(101)));
+        store.set("key0", json(0))?;
+        store.set("key0", json(0))?;
+        drop(store);
+
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.get("expiring")?, Some(json(100)));
+        for i in 0..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(json(i)));
+        }
+        let scanned: Vec<KvPair> = store.scan_prefix("key9")?.collect::<Result<_>>()?;
+        assert_eq!(scanned[0], (b"key9".to_vec(), json(9).into_bytes()));
+    }
+
+    // Both codecs shrink repetitive values several times over.
+    assert!(sizes[1] * 3 < sizes[0], "sizes: {:?}", sizes);
+    assert!(sizes[2] * 3 < sizes[0], "sizes: {:?}", sizes);
+
+    Ok(())
+}
+
+/// Returns `true` if any file in `log_dir` holds `needle`.
+fn files_contain(log_dir: &std::path::Path, needle: &[u8]) -> bool {
+    fs::read_dir(log_dir).unwrap().any(|entry| {
+        fs::read(entry.unwrap().path())
+            .unwrap()
+            .windows(needle.len())
+            .any(|window| window == needle)
+    })
+}
+
+// Encrypted stores should keep keys and values out of their files, and only
+// open with the right key
+#[test]
+fn encryption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_dir = temp_dir.path().join(".kvsdata");
+    let options = KvStoreOptions::new()
+        .encryption_key(EncryptionKey::new([1; 32]))
+        .compression(Codec::Snappy);
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("secret-key", "secret-value")?;
+    store.set("long-key", "secret-value".repeat(100))?;
+    store.set_with_ttl("expiring-key", "secret-value", Duration::from_secs(3600))?;
+    let mut batch = WriteBatch::new();
+    batch.put("batched-key", "secret-value").delete("long-key");
+    store.write_batch(batch)?;
+    let mut snapshot = store.snapshot();
+    assert_eq!(snapshot.get("secret-key")?, Some(b"secret-value".to_vec()));
+    drop(snapshot);
+    drop(store);
+    assert!(!files_contain(&log_dir, b"secret"));
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("secret-key")?, Some("secret-value".to_owned()));
+        assert_eq!(store.get("long-key")?, None);
+        assert_eq!(store.get("expiring-key")?, Some("secret-value".to_owned()));
+        assert_eq!(store.get("batched-key")?, Some("secret-value".to_owned()));
+        Ok(())
+    };
+    check(&KvStore::open_with(temp_dir.path(), options.clone())?)?;
+    // Replay the log itself rather than its hints.
+    fs::remove_file(log_dir.join("1.hint"))?;
+    check(&KvStore::open_with(temp_dir.path(), options.clone())?)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::MissingKey { gen: 1 }) => {}
+        res => panic!("expected a missing key error, got {:?}", res.err()),
+    }
+    let wrong_key = KvStoreOptions::new().encryption_key(EncryptionKey::new([2; 32]));
+    match KvStore::open_with(temp_dir.path(), wrong_key) {
+        Err(KvsError::WrongKey { gen: 1 }) => {}
+        res => panic!("expected a wrong key error, got {:?}", res.err()),
+    }
+
+    Ok(())
+}
+
+// A sealed entry should only unseal where it was written, so that an old
+// entry cannot be replayed over a newer one, while compaction should still
+// move entries freely.
+#[test]
+fn encrypted_entries_are_bound_to_their_position() -> Result<()> {
+    let options = KvStoreOptions::new().encryption_key(EncryptionKey::new([1; 32]));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let compacting = options
+        .clone()
+        .compaction_trigger(CompactionTrigger::Bytes(1));
+    let store = KvStore::open_with(temp_dir.path(), compacting.clone())?;
+    store.set("key1", "value1")?;
+    store.set("key1", "value2")?;
+    store.wait_for_compaction();
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), compacting)?;
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_dir = temp_dir.path().join(".kvsdata");
+    let log_path = log_dir.join("1.log");
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let header_len = fs::metadata(&log_path)?.len() as usize;
+    store.set("key1", "value1")?;
+    store.set("key1", "value2")?;
+    drop(store);
+
+    // Both entries are the same length, so copy the first over the second.
+    let mut log = fs::read(&log_path)?;
+    let second = header_len + (log.len() - header_len) / 2;
+    log.copy_within(header_len..second, second);
+    fs::write(&log_path, log)?;
+    fs::remove_file(log_dir.join("1.hint"))?;
+
+    match KvStore::open_with(temp_dir.path(), options) {
+        Err(KvsError::Authentication { gen: 1, offset }) => assert_eq!(offset, second as u64),
+        res => panic!("expected an authentication error, got {:?}", res.err()),
+    }
+
+    Ok(())
+}
+
+// Opening a plaintext store with a key should encrypt its existing logs
+#[test]
+fn encrypt_existing_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_dir = temp_dir.path().join(".kvsdata");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("secret-key", "secret-value")?;
+    drop(store);
+    assert!(files_contain(&log_dir, b"secret"));
+
+    let options = KvStoreOptions::new().encryption_key(EncryptionKey::new([1; 32]));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("secret-key")?, Some("secret-value".to_owned()));
+    drop(store);
+    assert!(!files_contain(&log_dir, b"secret"));
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("secret-key")?, Some("secret-value".to_owned()));
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::MissingKey { .. })
+    ));
+
+    Ok(())
+}
+
+// Removing every key once an existing store was encrypted should account for
+// the overhead sealing added to each entry
+#[test]
+fn remove_after_encrypting_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value")?;
+    }
+    drop(store);
+
+    let options = KvStoreOptions::new().encryption_key(EncryptionKey::new([1; 32]));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.wait_for_compaction();
+    for key_id in 0..10 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+
+    Ok(())
+}